@@ -0,0 +1,296 @@
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, Mutex};
+
+use crate::config::{self, DacChannel};
+use crate::dac::{dac_status, dac_write, PeticionDac};
+use crate::persistencia::{cargar, guardar};
+
+pub type CalibracionDac = Arc<Mutex<Calibracion>>;
+
+//Los puntos medidos se responden en un byte
+const PUNTOS_MAXIMOS: usize = 255;
+
+#[derive(Default, Deserialize, Serialize)]
+struct Guardado {
+    channel: Vec<DacChannel>,
+}
+
+/* COEFICIENTES DE CADA CANAL Y PUNTOS MEDIDOS DE LA CALIBRACION EN CURSO */
+pub struct Calibracion {
+    archivo: String,
+    //Cantidad de codigos del DAC
+    codigos: f64,
+    canales: Vec<DacChannel>,
+    //(codigo, milivoltios medidos)
+    puntos: Vec<Vec<(u16, f64)>>,
+}
+
+impl Calibracion {
+    //Lo guardado por la ultima calibracion reemplaza a la configuracion
+    pub fn new(config: &config::Dac, bits: u8, verbose: bool) -> Self {
+        let guardado: Guardado = cargar(&config.calibration_file, verbose);
+        let canales = if guardado.channel.len() == config.channel.len()
+            && guardado
+                .channel
+                .iter()
+                .all(|c| c.vref_mv > 0.0 && c.gain > 0.0)
+        {
+            guardado.channel
+        } else {
+            if !guardado.channel.is_empty() {
+                println!(
+                    "Ignoring invalid dac calibration in {}",
+                    config.calibration_file
+                );
+            }
+            config.channel.clone()
+        };
+        Calibracion {
+            archivo: config.calibration_file.clone(),
+            codigos: (1u32 << bits) as f64,
+            puntos: vec![Vec::new(); canales.len()],
+            canales,
+        }
+    }
+
+    //Codigo que da los milivoltios pedidos, None si queda fuera de rango
+    fn codigo(&self, canal: usize, mv: f64) -> Option<u16> {
+        let c = &self.canales[canal];
+        let codigo =
+            ((mv - c.offset_mv as f64) / c.gain as f64 * self.codigos / c.vref_mv as f64).round();
+        (0.0..self.codigos)
+            .contains(&codigo)
+            .then_some(codigo as u16)
+    }
+
+    //Cuadrados minimos de lo medido contra la salida ideal del codigo
+    fn ajustar(&self, canal: usize) -> Result<DacChannel, String> {
+        let vref = self.canales[canal].vref_mv as f64;
+        let puntos = &self.puntos[canal];
+        let n = puntos.len() as f64;
+        let (mut sx, mut sy, mut sxx, mut sxy) = (0.0, 0.0, 0.0, 0.0);
+        for (codigo, medido) in puntos {
+            let ideal = *codigo as f64 * vref / self.codigos;
+            sx += ideal;
+            sy += medido;
+            sxx += ideal * ideal;
+            sxy += ideal * medido;
+        }
+        let divisor = n * sxx - sx * sx;
+        if puntos.len() < 2 || divisor.abs() < f64::EPSILON {
+            return Err(String::from("at least two different codes needed"));
+        }
+        let gain = (n * sxy - sx * sy) / divisor;
+        if gain <= 0.0 {
+            return Err(String::from("output does not rise with the code"));
+        }
+        Ok(DacChannel {
+            vref_mv: vref as f32,
+            gain: gain as f32,
+            offset_mv: ((sy - gain * sx) / n) as f32,
+        })
+    }
+
+    //[estado, puntos medidos, vref_mv (f32), gain (f32), offset_mv (f32)]
+    fn bytes(&self, canal: usize, estado: u8) -> Vec<u8> {
+        let c = &self.canales[canal];
+        let mut respuesta = vec![estado, self.puntos[canal].len() as u8];
+        respuesta.extend_from_slice(&c.vref_mv.to_be_bytes());
+        respuesta.extend_from_slice(&c.gain.to_be_bytes());
+        respuesta.extend_from_slice(&c.offset_mv.to_be_bytes());
+        respuesta
+    }
+}
+
+//msg: [opcode, canal, milivoltios (u16)]
+pub async fn dac_millivolts(
+    msg: u32,
+    calibracion: &CalibracionDac,
+    rx: &mut tokio::sync::broadcast::Receiver<Vec<u8>>,
+    tx: &tokio::sync::mpsc::Sender<PeticionDac>,
+    verbose: bool,
+) -> [u8; 2] {
+    let canal = ((msg >> 16) & 0x0F) as usize;
+    let mv = (msg & 0xFFFF) as f64;
+    let codigo = if canal > 7 {
+        None
+    } else {
+        calibracion.lock().unwrap().codigo(canal, mv)
+    };
+    let Some(codigo) = codigo else {
+        if verbose {
+            println!("Dac channel {} can not output {} mV", canal, mv);
+        }
+        return [0xF0, 0xF0];
+    };
+    if verbose {
+        println!("Dac channel {}: {} mV is code {}", canal, mv, codigo);
+    }
+    dac_write((canal as u32) << 16 | codigo as u32, rx, tx).await
+}
+
+//msg: [opcode, comando << 4 | canal, milivoltios medidos (u16)]
+//comando 0: empezar, 1: agregar lo medido con el codigo actual del canal,
+//2: calcular y guardar los coeficientes, 3: consultar
+pub async fn dac_calibration(
+    msg: u32,
+    calibracion: &CalibracionDac,
+    rx: &mut tokio::sync::broadcast::Receiver<Vec<u8>>,
+    tx: &tokio::sync::mpsc::Sender<PeticionDac>,
+    verbose: bool,
+) -> Vec<u8> {
+    let canal = ((msg >> 16) & 0x0F) as usize;
+    if canal > 7 {
+        return vec![0xF0];
+    }
+    let mut estado = 0;
+    match (msg >> 20) & 0x0F {
+        0 => calibracion.lock().unwrap().puntos[canal].clear(),
+        1 if calibracion.lock().unwrap().puntos[canal].len() >= PUNTOS_MAXIMOS => {
+            if verbose {
+                println!(
+                    "Dac channel {} already has {} measurements",
+                    canal, PUNTOS_MAXIMOS
+                );
+            }
+            estado = 0xF0;
+        }
+        1 => {
+            //El canal tiene que estar encendido y el DAC responder bien
+            let respuesta = dac_status((canal as u32) << 16, rx, tx).await;
+            if respuesta.len() == 4 && respuesta[3] & 0x03 == 0x01 {
+                let codigo = u16::from_be_bytes([respuesta[1], respuesta[2]]);
+                let medido = (msg & 0xFFFF) as f64;
+                if verbose {
+                    println!(
+                        "Dac channel {}: code {} measured {} mV",
+                        canal, codigo, medido
+                    );
+                }
+                calibracion.lock().unwrap().puntos[canal].push((codigo, medido));
+            } else {
+                if verbose {
+                    println!("Dac channel {} is off or did not answer", canal);
+                }
+                estado = 0xF0;
+            }
+        }
+        2 => {
+            let mut calibracion = calibracion.lock().unwrap();
+            match calibracion.ajustar(canal) {
+                Ok(coeficientes) => {
+                    calibracion.canales[canal] = coeficientes;
+                    calibracion.puntos[canal].clear();
+                    if verbose {
+                        println!(
+                            "Dac channel {} calibrated: gain {}, offset {} mV",
+                            canal, coeficientes.gain, coeficientes.offset_mv
+                        );
+                    }
+                    let guardado = Guardado {
+                        channel: calibracion.canales.clone(),
+                    };
+                    if !guardar(&calibracion.archivo, &guardado, verbose) {
+                        estado = 0xF0;
+                    }
+                }
+                Err(e) => {
+                    if verbose {
+                        println!("Dac channel {} not calibrated: {}", canal, e);
+                    }
+                    estado = 0xF0;
+                }
+            }
+        }
+        3 => {}
+        _ => estado = 0xF0,
+    }
+    calibracion.lock().unwrap().bytes(canal, estado)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //10 bits y 1024 mV de referencia: el codigo ideal son los milivoltios
+    fn calibracion(gain: f32, offset_mv: f32, puntos: &[(u16, f64)]) -> Calibracion {
+        Calibracion {
+            archivo: String::new(),
+            codigos: 1024.0,
+            canales: vec![DacChannel {
+                vref_mv: 1024.0,
+                gain,
+                offset_mv,
+            }],
+            puntos: vec![puntos.to_vec()],
+        }
+    }
+
+    #[test]
+    fn codigo_ideal() {
+        let calibracion = calibracion(1.0, 0.0, &[]);
+        assert_eq!(calibracion.codigo(0, 0.0), Some(0));
+        assert_eq!(calibracion.codigo(0, 512.4), Some(512));
+        assert_eq!(calibracion.codigo(0, 1023.0), Some(1023));
+    }
+
+    #[test]
+    fn codigo_con_ganancia_y_offset() {
+        let calibracion = calibracion(2.0, 100.0, &[]);
+        assert_eq!(calibracion.codigo(0, 300.0), Some(100));
+        assert_eq!(calibracion.codigo(0, 100.0), Some(0));
+    }
+
+    #[test]
+    fn codigo_fuera_de_rango() {
+        let calibracion = calibracion(2.0, 100.0, &[]);
+        assert_eq!(calibracion.codigo(0, 50.0), None);
+        assert_eq!(calibracion.codigo(0, 2148.0), None);
+        assert_eq!(calibracion.codigo(0, 2146.0), Some(1023));
+    }
+
+    #[test]
+    fn ajuste_exacto() {
+        let calibracion = calibracion(1.0, 0.0, &[(100, 210.0), (500, 1010.0), (900, 1810.0)]);
+        let Ok(canal) = calibracion.ajustar(0) else {
+            panic!("fit failed");
+        };
+        assert_eq!(canal.vref_mv, 1024.0);
+        assert!((canal.gain - 2.0).abs() < 1e-4);
+        assert!((canal.offset_mv - 10.0).abs() < 1e-3);
+    }
+
+    #[test]
+    fn ajuste_con_ruido() {
+        let calibracion = calibracion(
+            1.0,
+            0.0,
+            &[(0, 5.0), (256, 257.0), (512, 513.0), (768, 773.0)],
+        );
+        let Ok(canal) = calibracion.ajustar(0) else {
+            panic!("fit failed");
+        };
+        assert!((canal.gain - 1.0).abs() < 1e-4);
+        assert!((canal.offset_mv - 3.0).abs() < 1e-3);
+    }
+
+    #[test]
+    fn ajuste_degenerado() {
+        for puntos in [&[][..], &[(100, 100.0)], &[(100, 100.0), (100, 120.0)]] {
+            assert_eq!(
+                calibracion(1.0, 0.0, puntos).ajustar(0).err().as_deref(),
+                Some("at least two different codes needed")
+            );
+        }
+    }
+
+    #[test]
+    fn ajuste_con_ganancia_negativa() {
+        for puntos in [&[(100, 500.0), (900, 100.0)], &[(100, 500.0), (900, 500.0)]] {
+            assert_eq!(
+                calibracion(1.0, 0.0, puntos).ajustar(0).err().as_deref(),
+                Some("output does not rise with the code")
+            );
+        }
+    }
+}
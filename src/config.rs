@@ -0,0 +1,871 @@
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::ErrorKind;
+
+pub const CONFIG_PATH: &str = "/etc/sspa/sspa.toml";
+
+/* CONFIGURACION DESDE ARCHIVO TOML */
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub safe_state: SafeState,
+    pub watchdog: Watchdog,
+    pub sequence: Vec<Sequence>,
+    pub relay: Vec<Relay>,
+    pub relay_bank: RelayBank,
+    pub spi_device: Vec<SpiDevice>,
+    pub spi_test: SpiTest,
+    pub spi_trace: SpiTrace,
+    pub register_map: RegisterMap,
+    pub register_dump: RegisterDump,
+    pub programmer: Programmer,
+    pub firmware: Firmware,
+    pub dac: Dac,
+    pub pwm_dac: PwmDac,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            safe_state: SafeState::default(),
+            watchdog: Watchdog::default(),
+            sequence: Vec::new(),
+            relay: vec![
+                Relay {
+                    name: String::from("reset"),
+                    pin: 12,
+                    active_low: true,
+                    safe_state: false,
+                    initial: None,
+                    end_of_life: None,
+                },
+                Relay {
+                    name: String::from("program"),
+                    pin: 0,
+                    active_low: true,
+                    safe_state: false,
+                    initial: None,
+                    end_of_life: None,
+                },
+            ],
+            relay_bank: RelayBank::default(),
+            spi_device: vec![
+                SpiDevice {
+                    name: String::from("pic"),
+                    bus: 0,
+                    slave_select: 0,
+                    mode: 1,
+                    clock_hz: 100000,
+                    bit_order: BitOrder::MsbFirst,
+                    gap_us: 100,
+                },
+                SpiDevice {
+                    name: String::from("dac"),
+                    bus: 0,
+                    slave_select: 1,
+                    mode: 0,
+                    clock_hz: 1000000,
+                    bit_order: BitOrder::MsbFirst,
+                    gap_us: 0,
+                },
+            ],
+            spi_test: SpiTest::default(),
+            spi_trace: SpiTrace::default(),
+            register_map: RegisterMap::default(),
+            register_dump: RegisterDump::default(),
+            programmer: Programmer::default(),
+            firmware: Firmware::default(),
+            dac: Dac::default(),
+            pwm_dac: PwmDac::default(),
+        }
+    }
+}
+
+/* Estado al que se llevan las salidas al apagar */
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SafeState {
+    pub timeout_ms: u64,
+}
+
+impl Default for SafeState {
+    fn default() -> Self {
+        SafeState { timeout_ms: 2000 }
+    }
+}
+
+/* Banco de relays, el indice en el protocolo es la posicion en el archivo */
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Relay {
+    pub name: String,
+    pub pin: u8,
+    #[serde(default)]
+    pub active_low: bool,
+    #[serde(default)]
+    pub safe_state: bool,
+    //Sin estado inicial el pin arranca en bajo
+    pub initial: Option<bool>,
+    pub end_of_life: Option<u32>,
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct RelayBank {
+    pub persist: bool,
+    pub state_file: String,
+    pub cycles_file: String,
+}
+
+impl Default for RelayBank {
+    fn default() -> Self {
+        RelayBank {
+            persist: false,
+            state_file: String::from("/var/lib/sspa/relays.toml"),
+            cycles_file: String::from("/var/lib/sspa/relay_cycles.toml"),
+        }
+    }
+}
+
+/* Vuelve las salidas a estado seguro si el cliente que las cambió desaparece */
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Watchdog {
+    pub enabled: bool,
+    pub timeout_ms: u64,
+}
+
+impl Default for Watchdog {
+    fn default() -> Self {
+        Watchdog {
+            enabled: false,
+            timeout_ms: 5000,
+        }
+    }
+}
+
+/* Dispositivos SPI, el indice en el protocolo es la posicion en el archivo */
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct SpiDevice {
+    pub name: String,
+    pub bus: u8,
+    pub slave_select: u8,
+    pub mode: u8,
+    pub clock_hz: u32,
+    #[serde(default)]
+    pub bit_order: BitOrder,
+    #[serde(default)]
+    pub gap_us: u64,
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum BitOrder {
+    #[default]
+    MsbFirst,
+    LsbFirst,
+}
+
+/* Pruebas de escritura y lectura del SPI con el PIC */
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SpiTest {
+    //Registro del PIC que se puede pisar sin efectos, sin el no se escribe el PIC
+    pub scratch_register: Option<u8>,
+    //Barrido de reloj por defecto
+    pub sweep_start_khz: u16,
+    pub sweep_stop_khz: u16,
+    pub sweep_step_khz: u16,
+    pub sweep_patterns: u32,
+}
+
+impl Default for SpiTest {
+    fn default() -> Self {
+        SpiTest {
+            scratch_register: None,
+            sweep_start_khz: 100,
+            sweep_stop_khz: 1000,
+            sweep_step_khz: 100,
+            sweep_patterns: 100,
+        }
+    }
+}
+
+/* Traza de las transacciones SPI */
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SpiTrace {
+    //Grabar desde el arranque
+    pub enabled: bool,
+    pub capacity: usize,
+    //Bytes de texto de una descarga
+    pub max_download_size: usize,
+}
+
+impl Default for SpiTrace {
+    fn default() -> Self {
+        SpiTrace {
+            enabled: false,
+            capacity: 10000,
+            max_download_size: 16 << 20,
+        }
+    }
+}
+
+/* Registros del PIC con nombre */
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RegisterMap {
+    pub file: String,
+}
+
+impl Default for RegisterMap {
+    fn default() -> Self {
+        RegisterMap {
+            file: String::from("/etc/sspa/pic_registers.toml"),
+        }
+    }
+}
+
+/* Registros del PIC que se leen de una vez y se comparan con los de referencia */
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RegisterDump {
+    //Vacio: todos los registros legibles del mapa
+    pub registers: Vec<String>,
+    pub snapshot_file: String,
+    pub golden_file: String,
+}
+
+impl Default for RegisterDump {
+    fn default() -> Self {
+        RegisterDump {
+            registers: Vec::new(),
+            snapshot_file: String::from("/var/lib/sspa/registers_snapshot.toml"),
+            golden_file: String::from("/etc/sspa/registers_golden.toml"),
+        }
+    }
+}
+
+/* Programador ICSP del PIC, sin pines configurados no se puede programar */
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Programmer {
+    pub pgc_pin: Option<u8>,
+    pub pgd_pin: Option<u8>,
+    //Memoria de programa y fila de escritura en palabras
+    pub flash_words: u32,
+    pub row_words: u32,
+    pub config_address: u32,
+    pub config_words: u32,
+}
+
+impl Default for Programmer {
+    fn default() -> Self {
+        Programmer {
+            pgc_pin: None,
+            pgd_pin: None,
+            flash_words: 8192,
+            row_words: 32,
+            config_address: 0x8007,
+            config_words: 2,
+        }
+    }
+}
+
+/* Imagenes de firmware y mapa de memoria del dispositivo, direcciones en bytes */
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Firmware {
+    //Donde empiezan las imagenes binarias
+    pub binary_address: u32,
+    pub max_size: usize,
+    pub region: Vec<MemoryRegion>,
+}
+
+impl Default for Firmware {
+    fn default() -> Self {
+        //PIC16F1 con 8K palabras, dos bytes por palabra
+        Firmware {
+            binary_address: 0,
+            max_size: 1 << 20,
+            region: vec![
+                MemoryRegion {
+                    name: String::from("program"),
+                    start: 0x0000,
+                    size: 0x4000,
+                },
+                MemoryRegion {
+                    name: String::from("user_id"),
+                    start: 0x10000,
+                    size: 8,
+                },
+                MemoryRegion {
+                    name: String::from("config"),
+                    start: 0x1000E,
+                    size: 4,
+                },
+            ],
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MemoryRegion {
+    pub name: String,
+    pub start: u32,
+    pub size: u32,
+}
+
+impl MemoryRegion {
+    pub fn contiene(&self, direccion: u32) -> bool {
+        direccion >= self.start && direccion - self.start < self.size
+    }
+}
+
+/* Chip del DAC y calibracion de sus canales, la del archivo de calibracion tiene prioridad */
+#[derive(Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct Dac {
+    pub chip: DacChip,
+    //Solo para los chips por I2C
+    pub i2c_bus: u8,
+    pub i2c_address: u16,
+    pub calibration_file: String,
+    pub channel: Vec<DacChannel>,
+}
+
+impl Default for Dac {
+    fn default() -> Self {
+        Dac {
+            chip: DacChip::default(),
+            i2c_bus: 1,
+            i2c_address: 0x48,
+            calibration_file: String::from("/var/lib/sspa/dac_calibration.toml"),
+            channel: vec![DacChannel::default(); 8],
+        }
+    }
+}
+
+#[derive(Deserialize, Default, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum DacChip {
+    //El DAC de 10 bits de los primeros fixtures
+    #[default]
+    Spi10,
+    Ad5628,
+    Ad5648,
+    Ad5668,
+    Dac5578,
+    Dac6578,
+    Dac7578,
+}
+
+impl DacChip {
+    pub fn bits(&self) -> u8 {
+        match self {
+            DacChip::Dac5578 => 8,
+            DacChip::Spi10 | DacChip::Dac6578 => 10,
+            DacChip::Ad5628 | DacChip::Dac7578 => 12,
+            DacChip::Ad5648 => 14,
+            DacChip::Ad5668 => 16,
+        }
+    }
+
+    pub fn spi(&self) -> bool {
+        !matches!(self, DacChip::Dac5578 | DacChip::Dac6578 | DacChip::Dac7578)
+    }
+}
+
+/* Salidas PWM que reemplazan al DAC con --hat, un canal por pin */
+#[derive(Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct PwmDac {
+    pub pins: Vec<u8>,
+    pub frequency_hz: f64,
+    pub bits: u8,
+    //Pines que el dtoverlay conecta a un canal PWM por hardware
+    pub hardware_pins: Vec<u8>,
+}
+
+impl Default for PwmDac {
+    fn default() -> Self {
+        PwmDac {
+            pins: vec![20, 21, 16, 19, 13, 6, 5, 26],
+            frequency_hz: 10000.0,
+            bits: 10,
+            hardware_pins: Vec::new(),
+        }
+    }
+}
+
+//Canal PWM por hardware de cada pin: 12 y 18 el 0, 13 y 19 el 1
+pub fn canal_pwm(pin: u8) -> Option<u8> {
+    match pin {
+        12 | 18 => Some(0),
+        13 | 19 => Some(1),
+        _ => None,
+    }
+}
+
+//Salida = gain * codigo * vref_mv / fondo de escala + offset_mv
+#[derive(Deserialize, Serialize, Clone, Copy)]
+#[serde(default, deny_unknown_fields)]
+pub struct DacChannel {
+    pub vref_mv: f32,
+    pub gain: f32,
+    pub offset_mv: f32,
+}
+
+impl Default for DacChannel {
+    fn default() -> Self {
+        DacChannel {
+            vref_mv: 3300.0,
+            gain: 1.0,
+            offset_mv: 0.0,
+        }
+    }
+}
+
+/* Secuencias de encendido con nombre */
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Sequence {
+    pub name: String,
+    pub steps: Vec<Step>,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "action", rename_all = "snake_case", deny_unknown_fields)]
+pub enum Step {
+    PowerEnable { value: bool },
+    Relay { relay: String, value: bool },
+    Dac { channel: u8, code: u16 },
+    TnrRegister { address: u8, value: u16 },
+    TnrStart,
+    SpiWrite { address: u8, value: u16 },
+    Delay { ms: u64 },
+}
+
+//Sin archivo especificado y sin el de por defecto, se usan los valores por defecto
+pub fn cargar(path: Option<&str>) -> Result<Config, String> {
+    let contenido = match fs::read_to_string(path.unwrap_or(CONFIG_PATH)) {
+        Ok(c) => c,
+        Err(e) if path.is_none() && e.kind() == ErrorKind::NotFound => String::new(),
+        Err(e) => {
+            return Err(format!(
+                "Failed to read {}: {}",
+                path.unwrap_or(CONFIG_PATH),
+                e
+            ))
+        }
+    };
+
+    let config: Config =
+        toml::from_str(&contenido).map_err(|e| format!("Invalid config: {}", e))?;
+    validar(&config)?;
+    Ok(config)
+}
+
+fn validar(config: &Config) -> Result<(), String> {
+    if config.relay.len() > 16 {
+        return Err(String::from("Invalid config: at most 16 relays"));
+    }
+    for (n, relay) in config.relay.iter().enumerate() {
+        if config.relay[..n].iter().any(|r| r.name == relay.name) {
+            return Err(format!(
+                "Invalid config: relay {} defined twice",
+                relay.name
+            ));
+        }
+        if config.relay[..n].iter().any(|r| r.pin == relay.pin) {
+            return Err(format!("Invalid config: gpio {} used twice", relay.pin));
+        }
+    }
+
+    if config.sequence.len() > 128 {
+        return Err(String::from("Invalid config: at most 128 sequences"));
+    }
+    for (n, secuencia) in config.sequence.iter().enumerate() {
+        if config.sequence[..n]
+            .iter()
+            .any(|s| s.name == secuencia.name)
+        {
+            return Err(format!(
+                "Invalid config: sequence {} defined twice",
+                secuencia.name
+            ));
+        }
+    }
+
+    if config.spi_device.len() > 16 {
+        return Err(String::from("Invalid config: at most 16 spi devices"));
+    }
+    if !config.spi_device.iter().any(|d| d.name == "pic") {
+        return Err(String::from("Invalid config: missing spi device pic"));
+    }
+    for (n, dispositivo) in config.spi_device.iter().enumerate() {
+        if config.spi_device[..n]
+            .iter()
+            .any(|d| d.name == dispositivo.name)
+        {
+            return Err(format!(
+                "Invalid config: spi device {} defined twice",
+                dispositivo.name
+            ));
+        }
+        if dispositivo.bus > 6 || dispositivo.slave_select > 15 || dispositivo.mode > 3 {
+            return Err(format!(
+                "Invalid config: spi device {} bus, slave_select or mode out of range",
+                dispositivo.name
+            ));
+        }
+    }
+
+    let programador = &config.programmer;
+    for pin in [programador.pgc_pin, programador.pgd_pin]
+        .into_iter()
+        .flatten()
+    {
+        if config.relay.iter().any(|r| r.pin == pin) {
+            return Err(format!("Invalid config: gpio {} used twice", pin));
+        }
+    }
+    if programador.pgc_pin.is_some() && programador.pgc_pin == programador.pgd_pin {
+        return Err(String::from(
+            "Invalid config: pgc_pin and pgd_pin are the same",
+        ));
+    }
+    if programador.row_words == 0
+        || !programador
+            .flash_words
+            .is_multiple_of(programador.row_words)
+        || programador.config_address < 0x8000
+        || programador
+            .config_address
+            .checked_add(programador.config_words)
+            .is_none_or(|fin| fin > 0x8010)
+    {
+        return Err(String::from(
+            "Invalid config: programmer flash_words must be a multiple of row_words and config words within 0x8000 to 0x800F",
+        ));
+    }
+
+    if config.dac.channel.len() != 8 {
+        return Err(String::from("Invalid config: dac needs 8 channels"));
+    }
+    if config
+        .dac
+        .channel
+        .iter()
+        .any(|c| c.vref_mv <= 0.0 || c.gain <= 0.0)
+    {
+        return Err(String::from(
+            "Invalid config: dac vref_mv and gain must be positive",
+        ));
+    }
+
+    let pwm = &config.pwm_dac;
+    if pwm.pins.is_empty() || pwm.pins.len() > 8 {
+        return Err(String::from("Invalid config: pwm_dac needs 1 to 8 pins"));
+    }
+    for (n, pin) in pwm.pins.iter().enumerate() {
+        if *pin > 27 || pwm.pins[..n].contains(pin) {
+            return Err(format!(
+                "Invalid config: pwm_dac gpio {} invalid or used twice",
+                pin
+            ));
+        }
+    }
+    if !(1..=16).contains(&pwm.bits) || pwm.frequency_hz <= 0.0 {
+        return Err(String::from(
+            "Invalid config: pwm_dac bits or frequency_hz out of range",
+        ));
+    }
+    for (n, pin) in pwm.hardware_pins.iter().enumerate() {
+        if !pwm.pins.contains(pin)
+            || canal_pwm(*pin).is_none()
+            || pwm.hardware_pins[..n]
+                .iter()
+                .any(|p| canal_pwm(*p) == canal_pwm(*pin))
+        {
+            return Err(format!(
+                "Invalid config: pwm_dac gpio {} has no hardware pwm channel free",
+                pin
+            ));
+        }
+    }
+
+    if !config.dac.chip.spi() && config.dac.i2c_address > 0x7F {
+        return Err(format!(
+            "Invalid config: dac i2c_address 0x{:X} out of range",
+            config.dac.i2c_address
+        ));
+    }
+
+    for (n, region) in config.firmware.region.iter().enumerate() {
+        if region.size == 0 || region.start.checked_add(region.size - 1).is_none() {
+            return Err(format!(
+                "Invalid config: memory region {} size out of range",
+                region.name
+            ));
+        }
+        if config.firmware.region[..n]
+            .iter()
+            .any(|r| r.name == region.name || r.contiene(region.start) || region.contiene(r.start))
+        {
+            return Err(format!(
+                "Invalid config: memory region {} defined twice or overlapping",
+                region.name
+            ));
+        }
+    }
+
+    let prueba = &config.spi_test;
+    if prueba.sweep_start_khz == 0
+        || prueba.sweep_step_khz == 0
+        || prueba.sweep_start_khz > prueba.sweep_stop_khz
+    {
+        return Err(String::from(
+            "Invalid config: spi_test sweep needs 0 < sweep_start_khz <= sweep_stop_khz and sweep_step_khz > 0",
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn desde(texto: &str) -> Config {
+        toml::from_str(texto).unwrap()
+    }
+
+    #[test]
+    fn la_configuracion_por_defecto_es_valida() {
+        assert_eq!(validar(&Config::default()), Ok(()));
+    }
+
+    #[test]
+    fn palabras_de_configuracion_fuera_de_rango() {
+        let mut config = Config::default();
+        config.programmer.config_words = 10;
+        assert!(validar(&config).is_err());
+        config.programmer.config_address = u32::MAX;
+        config.programmer.config_words = 2;
+        assert!(validar(&config).is_err());
+        config.programmer.config_address = 0x8000;
+        config.programmer.config_words = 16;
+        assert_eq!(validar(&config), Ok(()));
+    }
+
+    #[test]
+    fn relays_repetidos_o_de_mas() {
+        let config = desde(
+            r#"
+            [[relay]]
+            name = "a"
+            pin = 5
+            [[relay]]
+            name = "a"
+            pin = 6
+            "#,
+        );
+        assert_eq!(
+            validar(&config),
+            Err(String::from("Invalid config: relay a defined twice"))
+        );
+        let config = desde(
+            r#"
+            [[relay]]
+            name = "a"
+            pin = 5
+            [[relay]]
+            name = "b"
+            pin = 5
+            "#,
+        );
+        assert_eq!(
+            validar(&config),
+            Err(String::from("Invalid config: gpio 5 used twice"))
+        );
+        let mut config = Config {
+            relay: (0..17)
+                .map(|n| Relay {
+                    name: format!("relay{}", n),
+                    pin: n,
+                    active_low: false,
+                    safe_state: false,
+                    initial: None,
+                    end_of_life: None,
+                })
+                .collect(),
+            ..Config::default()
+        };
+        assert!(validar(&config).is_err());
+        config.relay.pop();
+        assert_eq!(validar(&config), Ok(()));
+    }
+
+    #[test]
+    fn secuencias_repetidas() {
+        let config = desde(
+            r#"
+            [[sequence]]
+            name = "encender"
+            steps = [{ action = "delay", ms = 10 }]
+            [[sequence]]
+            name = "encender"
+            steps = []
+            "#,
+        );
+        assert_eq!(
+            validar(&config),
+            Err(String::from(
+                "Invalid config: sequence encender defined twice"
+            ))
+        );
+        assert!(toml::from_str::<Config>(
+            r#"
+            [[sequence]]
+            name = "encender"
+            steps = [{ action = "blink" }]
+            "#,
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn dispositivos_spi() {
+        let config = desde(
+            r#"
+            [[spi_device]]
+            name = "dac"
+            bus = 0
+            slave_select = 1
+            mode = 1
+            clock_hz = 1000000
+            "#,
+        );
+        assert_eq!(
+            validar(&config),
+            Err(String::from("Invalid config: missing spi device pic"))
+        );
+        let config = desde(
+            r#"
+            [[spi_device]]
+            name = "pic"
+            bus = 0
+            slave_select = 0
+            mode = 4
+            clock_hz = 100000
+            "#,
+        );
+        assert!(validar(&config).is_err());
+        let config = desde(
+            r#"
+            [[spi_device]]
+            name = "pic"
+            bus = 0
+            slave_select = 0
+            mode = 1
+            clock_hz = 100000
+            bit_order = "lsb_first"
+            [[spi_device]]
+            name = "pic"
+            bus = 1
+            slave_select = 0
+            mode = 0
+            clock_hz = 100000
+            "#,
+        );
+        assert_eq!(
+            validar(&config),
+            Err(String::from("Invalid config: spi device pic defined twice"))
+        );
+    }
+
+    #[test]
+    fn barrido_de_reloj() {
+        for barrido in [
+            "sweep_start_khz = 0",
+            "sweep_step_khz = 0",
+            "sweep_start_khz = 500\nsweep_stop_khz = 400",
+        ] {
+            let config = desde(&format!("[spi_test]\n{}\n", barrido));
+            assert!(validar(&config).is_err(), "{}", barrido);
+        }
+        let config = desde("[spi_test]\nscratch_register = 0x20\nsweep_stop_khz = 100\n");
+        assert_eq!(validar(&config), Ok(()));
+    }
+
+    #[test]
+    fn regiones_de_memoria() {
+        let region = MemoryRegion {
+            name: String::from("program"),
+            start: 0x100,
+            size: 0x10,
+        };
+        assert!(!region.contiene(0xFF));
+        assert!(region.contiene(0x100));
+        assert!(region.contiene(0x10F));
+        assert!(!region.contiene(0x110));
+
+        for regiones in [
+            "{ name = \"a\", start = 0, size = 0 }",
+            "{ name = \"a\", start = 0xFFFFFFFF, size = 2 }",
+            "{ name = \"a\", start = 0, size = 16 }, { name = \"b\", start = 8, size = 16 }",
+            "{ name = \"a\", start = 0, size = 16 }, { name = \"a\", start = 16, size = 16 }",
+        ] {
+            let config = desde(&format!("[firmware]\nregion = [{}]\n", regiones));
+            assert!(validar(&config).is_err(), "{}", regiones);
+        }
+        let config = desde(
+            "[firmware]\nregion = [{ name = \"a\", start = 0, size = 16 }, { name = \"b\", start = 16, size = 16 }]\n",
+        );
+        assert_eq!(validar(&config), Ok(()));
+    }
+
+    #[test]
+    fn chips_del_dac() {
+        assert_eq!(DacChip::Dac5578.bits(), 8);
+        assert_eq!(DacChip::Ad5668.bits(), 16);
+        assert!(DacChip::Ad5648.spi());
+        assert!(!DacChip::Dac7578.spi());
+        let config = desde("[dac]\nchip = \"dac7578\"\ni2c_address = 0x80\n");
+        assert_eq!(
+            validar(&config),
+            Err(String::from(
+                "Invalid config: dac i2c_address 0x80 out of range"
+            ))
+        );
+        //Por SPI la direccion no se usa
+        let config = desde("[dac]\nchip = \"ad5628\"\ni2c_address = 0x80\n");
+        assert_eq!(validar(&config), Ok(()));
+    }
+
+    #[test]
+    fn salidas_pwm() {
+        assert_eq!(canal_pwm(18), Some(0));
+        assert_eq!(canal_pwm(13), Some(1));
+        assert_eq!(canal_pwm(20), None);
+        for pwm in [
+            "pins = []",
+            "pins = [20, 20]",
+            "pins = [28]",
+            "bits = 0",
+            "bits = 17",
+            "frequency_hz = 0.0",
+            "pins = [20, 21]\nhardware_pins = [20]",
+            "pins = [12, 18]\nhardware_pins = [12, 18]",
+            "pins = [12]\nhardware_pins = [13]",
+        ] {
+            let config = desde(&format!("[pwm_dac]\n{}\n", pwm));
+            assert!(validar(&config).is_err(), "{}", pwm);
+        }
+        let config = desde("[pwm_dac]\npins = [12, 13, 20]\nhardware_pins = [12, 13]\n");
+        assert_eq!(validar(&config), Ok(()));
+    }
+}
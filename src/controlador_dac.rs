@@ -0,0 +1,384 @@
+use rppal::i2c::I2c;
+use std::sync::{Arc, Mutex};
+use tokio::time::{sleep, Duration};
+
+use crate::config::{self, DacChip};
+use crate::dac::TASA_MAXIMA;
+use crate::spi::Dispositivo;
+use crate::traza::TrazaSpi;
+
+//Lo que queda en un canal despues de escribirlo o leerlo
+pub struct Lectura {
+    pub codigo: u16,
+    pub valido: bool,
+}
+
+/* CHIPS DE DAC, TODOS DE 8 CANALES */
+pub trait DacDriver {
+    fn bits(&self) -> u8;
+
+    //Muestras por segundo que puede seguir en las formas de onda
+    fn tasa_maxima(&self) -> u32 {
+        TASA_MAXIMA
+    }
+
+    //Deja el codigo listo en el canal, sin cambiar la salida
+    async fn cargar(&mut self, canal: u8, codigo: u16) -> Result<(), String>;
+
+    //Pasa a la salida lo cargado en los canales, devuelve lo que quedo en cada uno
+    async fn actualizar(&mut self, canales: &[u8]) -> Result<Vec<Lectura>, String>;
+
+    async fn leer(&mut self, canal: u8) -> Result<Lectura, String>;
+
+    //Dispositivo SPI del chip, para cambiar su configuracion
+    fn spi(&mut self) -> Option<&mut Dispositivo> {
+        None
+    }
+
+    async fn escribir(&mut self, canal: u8, codigo: u16) -> Result<Lectura, String> {
+        Ok(self.escribir_juntos(&[(canal, codigo)]).await?.remove(0))
+    }
+
+    async fn escribir_juntos(&mut self, codigos: &[(u8, u16)]) -> Result<Vec<Lectura>, String> {
+        for (canal, codigo) in codigos {
+            self.cargar(*canal, *codigo).await?;
+        }
+        let canales: Vec<u8> = codigos.iter().map(|(canal, _)| *canal).collect();
+        self.actualizar(&canales).await
+    }
+}
+
+/* DAC DE 10 BITS DE LOS PRIMEROS FIXTURES */
+//[canal, codigo alto << 4 | comando, codigo bajo], comando 0: escribir, 0xC: leer.
+//Despues de cargar se repite con la direccion | 0xC para actualizar y leer el canal
+pub struct Spi10 {
+    spi: Dispositivo,
+    cargados: [[u8; 3]; 8],
+    recibidos: [[u8; 3]; 8],
+    verbose: bool,
+}
+
+impl Spi10 {
+    pub fn abrir(dispositivo: &config::SpiDevice, traza: TrazaSpi, verbose: bool) -> Self {
+        Spi10 {
+            spi: Dispositivo::abrir(dispositivo)
+                .expect("Falló abrir dac")
+                .con_traza(traza),
+            cargados: [[0; 3]; 8],
+            recibidos: [[0; 3]; 8],
+            verbose,
+        }
+    }
+
+    async fn enviar(&self, msg: [u8; 3]) -> [u8; 3] {
+        let mut buffer = [0; 3];
+        self.spi.transfer(&mut buffer, &msg).await;
+        if self.verbose {
+            println!("Spi sent: {:02X}{:02X}{:02X}", msg[0], msg[1], msg[2]);
+            println!(
+                "Spi got: {:02X}{:02X}{:02X}",
+                buffer[0], buffer[1], buffer[2]
+            );
+        }
+        buffer
+    }
+}
+
+//000000dddddddddd <- Respuesta valida
+//100000dddddddddd <- Respuesta invalida
+fn lectura_spi10(buffer: [u8; 3]) -> Lectura {
+    let mut respuesta = [buffer[1], buffer[2]];
+    respuesta[0] |= !buffer[0].reverse_bits();
+    Lectura {
+        codigo: u16::from_be_bytes(respuesta) & 0x3FF,
+        valido: respuesta[0] & 0x80 == 0,
+    }
+}
+
+impl DacDriver for Spi10 {
+    fn bits(&self) -> u8 {
+        10
+    }
+
+    //Cada escritura espera 50 ms antes de actualizar
+    fn tasa_maxima(&self) -> u32 {
+        20
+    }
+
+    fn spi(&mut self) -> Option<&mut Dispositivo> {
+        Some(&mut self.spi)
+    }
+
+    async fn cargar(&mut self, canal: u8, codigo: u16) -> Result<(), String> {
+        let [alto, bajo] = codigo.to_be_bytes();
+        let msg = [canal, alto << 4, bajo];
+        self.recibidos[canal as usize] = self.enviar(msg).await;
+        self.cargados[canal as usize] = msg;
+        Ok(())
+    }
+
+    //Una sola espera para todos los canales y despues se actualizan uno tras otro
+    async fn actualizar(&mut self, canales: &[u8]) -> Result<Vec<Lectura>, String> {
+        sleep(Duration::from_millis(50)).await;
+        let mut lecturas = Vec::with_capacity(canales.len());
+        for canal in canales {
+            let msg = self.cargados[*canal as usize];
+            if canal & 0x0C == 0 {
+                self.recibidos[*canal as usize] = self.enviar([msg[0] | 0xC, msg[1], msg[2]]).await;
+            }
+            lecturas.push(lectura_spi10(self.recibidos[*canal as usize]));
+        }
+        Ok(lecturas)
+    }
+
+    async fn leer(&mut self, canal: u8) -> Result<Lectura, String> {
+        let mut recibido = self.enviar([canal, 0x0C, 0]).await;
+        sleep(Duration::from_millis(50)).await;
+        if canal & 0x0C == 0 {
+            recibido = self.enviar([canal | 0xC, 0x0C, 0]).await;
+        }
+        Ok(lectura_spi10(recibido))
+    }
+}
+
+/* Comandos comunes de los AD56x8 y los DAC7578 */
+const ESCRIBIR_ENTRADA: u8 = 0x0;
+const ACTUALIZAR: u8 = 0x1;
+const ESCRIBIR_Y_ACTUALIZAR: u8 = 0x3;
+//Direccion de todos los canales
+const TODOS: u8 = 0xF;
+
+/* AD5628, AD5648 Y AD5668: 12, 14 O 16 BITS POR SPI, SIN LECTURA */
+//Trama de 32 bits: comando << 24 | canal << 20 | codigo alineado al bit 19
+pub struct Ad56x8 {
+    spi: Dispositivo,
+    bits: u8,
+    cargados: [u16; 8],
+    salidas: [u16; 8],
+    verbose: bool,
+}
+
+impl Ad56x8 {
+    pub fn abrir(
+        chip: DacChip,
+        dispositivo: &config::SpiDevice,
+        traza: TrazaSpi,
+        verbose: bool,
+    ) -> Self {
+        Ad56x8 {
+            spi: Dispositivo::abrir(dispositivo)
+                .expect("Falló abrir dac")
+                .con_traza(traza),
+            bits: chip.bits(),
+            cargados: [0; 8],
+            salidas: [0; 8],
+            verbose,
+        }
+    }
+
+    async fn enviar(&self, comando: u8, canal: u8, codigo: u16) {
+        let trama = trama_ad56x8(comando, canal, codigo, self.bits);
+        let mut buffer = [0; 4];
+        self.spi.transfer(&mut buffer, &trama).await;
+        if self.verbose {
+            println!(
+                "Spi sent: {:02X}{:02X}{:02X}{:02X}",
+                trama[0], trama[1], trama[2], trama[3]
+            );
+        }
+    }
+}
+
+fn trama_ad56x8(comando: u8, canal: u8, codigo: u16, bits: u8) -> [u8; 4] {
+    ((comando as u32) << 24 | (canal as u32) << 20 | (codigo as u32) << (20 - bits)).to_be_bytes()
+}
+
+impl DacDriver for Ad56x8 {
+    fn bits(&self) -> u8 {
+        self.bits
+    }
+
+    fn spi(&mut self) -> Option<&mut Dispositivo> {
+        Some(&mut self.spi)
+    }
+
+    async fn cargar(&mut self, canal: u8, codigo: u16) -> Result<(), String> {
+        self.enviar(ESCRIBIR_ENTRADA, canal, codigo).await;
+        self.cargados[canal as usize] = codigo;
+        Ok(())
+    }
+
+    //Varios canales se actualizan con un solo comando a todos
+    async fn actualizar(&mut self, canales: &[u8]) -> Result<Vec<Lectura>, String> {
+        match canales {
+            [canal] => {
+                self.enviar(ACTUALIZAR, *canal, 0).await;
+                self.salidas[*canal as usize] = self.cargados[*canal as usize];
+            }
+            _ => {
+                self.enviar(ACTUALIZAR, TODOS, 0).await;
+                self.salidas = self.cargados;
+            }
+        }
+        let mut lecturas = Vec::with_capacity(canales.len());
+        for canal in canales {
+            lecturas.push(self.leer(*canal).await?);
+        }
+        Ok(lecturas)
+    }
+
+    //El chip no se puede leer, se devuelve lo ultimo que se paso a la salida
+    async fn leer(&mut self, canal: u8) -> Result<Lectura, String> {
+        Ok(Lectura {
+            codigo: self.salidas[canal as usize],
+            valido: true,
+        })
+    }
+
+    async fn escribir(&mut self, canal: u8, codigo: u16) -> Result<Lectura, String> {
+        self.enviar(ESCRIBIR_Y_ACTUALIZAR, canal, codigo).await;
+        self.cargados[canal as usize] = codigo;
+        self.salidas[canal as usize] = codigo;
+        self.leer(canal).await
+    }
+}
+
+/* DAC5578, DAC6578 Y DAC7578: 8, 10 O 12 BITS POR I2C */
+//[comando << 4 | canal, codigo alineado a la izquierda en 16 bits]
+pub struct Dac7578 {
+    i2c: Arc<Mutex<I2c>>,
+    bits: u8,
+    verbose: bool,
+}
+
+impl Dac7578 {
+    pub fn abrir(chip: DacChip, config: &config::Dac, verbose: bool) -> Self {
+        let mut i2c = I2c::with_bus(config.i2c_bus).expect("Falló abrir dac");
+        i2c.set_slave_address(config.i2c_address)
+            .expect("Falló abrir dac");
+        Dac7578 {
+            i2c: Arc::new(Mutex::new(i2c)),
+            bits: chip.bits(),
+            verbose,
+        }
+    }
+
+    //Como las transferencias SPI, fuera del runtime
+    async fn transferir(&self, enviado: Vec<u8>, largo: usize) -> Result<Vec<u8>, String> {
+        if self.verbose {
+            println!("I2c sent: {:02X?}", enviado);
+        }
+        let i2c = self.i2c.clone();
+        let leido = tokio::task::spawn_blocking(move || {
+            let mut i2c = i2c.lock().unwrap();
+            let mut leido = vec![0; largo];
+            if largo == 0 {
+                i2c.write(&enviado).map(|_| leido)
+            } else {
+                i2c.write_read(&enviado, &mut leido).map(|_| leido)
+            }
+        })
+        .await
+        .unwrap()
+        .map_err(|e| format!("i2c: {}", e))?;
+        if self.verbose && largo > 0 {
+            println!("I2c got: {:02X?}", leido);
+        }
+        Ok(leido)
+    }
+
+    async fn enviar(&self, comando: u8, canal: u8, codigo: u16) -> Result<(), String> {
+        let [alto, bajo] = (codigo << (16 - self.bits)).to_be_bytes();
+        self.transferir(vec![comando << 4 | canal, alto, bajo], 0)
+            .await
+            .map(|_| ())
+    }
+}
+
+impl DacDriver for Dac7578 {
+    fn bits(&self) -> u8 {
+        self.bits
+    }
+
+    async fn cargar(&mut self, canal: u8, codigo: u16) -> Result<(), String> {
+        self.enviar(ESCRIBIR_ENTRADA, canal, codigo).await
+    }
+
+    async fn actualizar(&mut self, canales: &[u8]) -> Result<Vec<Lectura>, String> {
+        match canales {
+            [canal] => self.enviar(ACTUALIZAR, *canal, 0).await?,
+            _ => self.enviar(ACTUALIZAR, TODOS, 0).await?,
+        }
+        let mut lecturas = Vec::with_capacity(canales.len());
+        for canal in canales {
+            lecturas.push(self.leer(*canal).await?);
+        }
+        Ok(lecturas)
+    }
+
+    //Lee el registro que esta en la salida
+    async fn leer(&mut self, canal: u8) -> Result<Lectura, String> {
+        let leido = self.transferir(vec![ACTUALIZAR << 4 | canal], 2).await?;
+        Ok(Lectura {
+            codigo: u16::from_be_bytes([leido[0], leido[1]]) >> (16 - self.bits),
+            valido: true,
+        })
+    }
+
+    async fn escribir(&mut self, canal: u8, codigo: u16) -> Result<Lectura, String> {
+        self.enviar(ESCRIBIR_Y_ACTUALIZAR, canal, codigo).await?;
+        self.leer(canal).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn spi10_valida() {
+        let lectura = lectura_spi10([0xFF, 0x03, 0xFF]);
+        assert_eq!(lectura.codigo, 0x3FF);
+        assert!(lectura.valido);
+        let lectura = lectura_spi10([0xFF, 0x01, 0x23]);
+        assert_eq!(lectura.codigo, 0x123);
+        assert!(lectura.valido);
+    }
+
+    #[test]
+    fn spi10_invalida() {
+        //El primer byte llega invertido y al reves, su bit 0 en cero marca la respuesta invalida
+        let lectura = lectura_spi10([0xFE, 0x01, 0x23]);
+        assert_eq!(lectura.codigo, 0x123);
+        assert!(!lectura.valido);
+        assert!(!lectura_spi10([0x00, 0x00, 0x00]).valido);
+        assert!(!lectura_spi10([0xFF, 0x80, 0x00]).valido);
+    }
+
+    #[test]
+    fn spi10_solo_10_bits() {
+        let lectura = lectura_spi10([0xFF, 0x7C, 0x00]);
+        assert_eq!(lectura.codigo, 0);
+        assert!(lectura.valido);
+    }
+
+    #[test]
+    fn tramas_ad56x8() {
+        assert_eq!(
+            trama_ad56x8(ESCRIBIR_Y_ACTUALIZAR, 2, 0xABCD, 16),
+            [0x03, 0x2A, 0xBC, 0xD0]
+        );
+        assert_eq!(
+            trama_ad56x8(ESCRIBIR_ENTRADA, 7, 0x3FFF, 14),
+            [0x00, 0x7F, 0xFF, 0xC0]
+        );
+        assert_eq!(
+            trama_ad56x8(ESCRIBIR_ENTRADA, 0, 0xFFF, 12),
+            [0x00, 0x0F, 0xFF, 0x00]
+        );
+        assert_eq!(
+            trama_ad56x8(ACTUALIZAR, TODOS, 0, 12),
+            [0x01, 0xF0, 0x00, 0x00]
+        );
+    }
+}
@@ -1,247 +1,907 @@
-use rppal::gpio::Gpio;
-use rppal::spi::*;
-use std::thread::sleep;
-use std::time::Duration;
+use rppal::gpio::{Gpio, OutputPin};
+use rppal::pwm::Channel;
+use std::f64::consts::PI;
+use tokio::time::{sleep_until, Duration, Instant};
+
+use crate::config::{self, Config, DacChip};
+use crate::controlador_dac::{Ad56x8, Dac7578, DacDriver, Lectura, Spi10};
+use crate::seguro::Listo;
+use crate::spi::configurar;
+use crate::traza::TrazaSpi;
+
+#[derive(Debug)]
+pub enum PeticionDac {
+    Escribir(u8, u16),
+    Leer(u8),
+    //Estado de los canales indicados
+    Estado(Vec<u8>),
+    //(canal, codigo) de varios canales que cambian juntos
+    Varios(Vec<(u8, u16)>),
+    //Forma de onda en un canal, reemplaza a la que estuviera
+    Reproducir(Reproduccion),
+    Detener(u8),
+    Reproduccion(u8),
+    //Comando y valor de configuracion del dispositivo SPI del chip
+    ConfigurarSpi(u8, u16),
+}
+
+//Lo ultimo escrito en cada canal, apagado hasta la primera escritura o despues del estado seguro
+#[derive(Clone, Copy)]
+struct CanalDac {
+    codigo: u16,
+    apagado: bool,
+}
+
+impl Default for CanalDac {
+    fn default() -> Self {
+        CanalDac {
+            codigo: 0,
+            apagado: true,
+        }
+    }
+}
+
+pub struct EstadoDac {
+    pub canal: u8,
+    pub codigo: u16,
+    pub valido: bool,
+    pub apagado: bool,
+}
+
+impl EstadoDac {
+    fn de_lectura(canal: u8, lectura: Lectura, apagado: bool) -> Self {
+        EstadoDac {
+            canal,
+            codigo: lectura.codigo,
+            valido: lectura.valido,
+            apagado,
+        }
+    }
+
+    //[canal, codigo (u16), estado], estado bit 0: valido, bit 1: apagado
+    fn bytes(&self) -> [u8; 4] {
+        let codigo = self.codigo.to_be_bytes();
+        [
+            self.canal,
+            codigo[0],
+            codigo[1],
+            self.valido as u8 | (self.apagado as u8) << 1,
+        ]
+    }
+}
+
+/* FORMAS DE ONDA */
+//Muestras por segundo que acepta el protocolo, cada salida puede aceptar menos
+pub const TASA_MAXIMA: u32 = 1000;
+
+#[derive(Debug)]
+pub struct Reproduccion {
+    canal: u8,
+    muestras: Vec<u16>,
+    tasa: u32,
+    periodo: Duration,
+    //0: sin fin
+    repeticiones: u32,
+    indice: usize,
+    vueltas: u32,
+    proxima: Instant,
+}
+
+impl Reproduccion {
+    //carga: [forma, muestras por segundo (u32), repeticiones (u32), parametros]
+    //forma 0: rampa, 1: seno, 2: cuadrada, con [minimo (u16), maximo (u16), muestras (u16)]
+    //3: tabla, con un codigo (u16) por muestra
+    fn de_carga(canal: u8, carga: &[u8]) -> Result<Self, String> {
+        if canal > 7 {
+            return Err(format!("channel {} out of range", canal));
+        }
+        if carga.len() < 9 {
+            return Err(String::from("missing waveform parameters"));
+        }
+        let tasa = u32::from_be_bytes([carga[1], carga[2], carga[3], carga[4]]);
+        let repeticiones = u32::from_be_bytes([carga[5], carga[6], carga[7], carga[8]]);
+        if !(1..=TASA_MAXIMA).contains(&tasa) {
+            return Err(format!("rate {} out of range", tasa));
+        }
+        let parametros: Vec<u16> = carga[9..]
+            .chunks_exact(2)
+            .map(|p| u16::from_be_bytes([p[0], p[1]]))
+            .collect();
+
+        let muestras: Vec<u16> = match (carga[0], parametros.as_slice()) {
+            (0..=2, [minimo, maximo, n]) if *n >= 2 && minimo <= maximo => {
+                let (minimo, maximo, n) = (*minimo as f64, *maximo as f64, *n as usize);
+                (0..n)
+                    .map(|i| match carga[0] {
+                        0 => minimo + (maximo - minimo) * i as f64 / (n - 1) as f64,
+                        1 => {
+                            let fase = 2.0 * PI * i as f64 / n as f64;
+                            (minimo + maximo) / 2.0 + (maximo - minimo) / 2.0 * fase.sin()
+                        }
+                        _ if i < n / 2 => maximo,
+                        _ => minimo,
+                    })
+                    .map(|codigo| codigo.round() as u16)
+                    .collect()
+            }
+            (3, tabla) if !tabla.is_empty() => tabla.to_vec(),
+            _ => return Err(String::from("invalid waveform")),
+        };
+        Ok(Reproduccion {
+            canal,
+            muestras,
+            tasa,
+            periodo: Duration::from_secs(1) / tasa,
+            repeticiones,
+            indice: 0,
+            vueltas: 0,
+            proxima: Instant::now(),
+        })
+    }
+
+    fn terminada(&self) -> bool {
+        self.repeticiones != 0 && self.vueltas >= self.repeticiones
+    }
+
+    //Si una muestra se atrasa la siguiente sale enseguida, sin acumular atraso
+    fn avanzar(&mut self) -> u16 {
+        let codigo = self.muestras[self.indice];
+        self.indice += 1;
+        if self.indice == self.muestras.len() {
+            self.indice = 0;
+            self.vueltas += 1;
+        }
+        self.proxima = (self.proxima + self.periodo).max(Instant::now());
+        codigo
+    }
+}
+
+//[activa, vueltas hechas (u32), repeticiones (u32)]
+fn estado_reproduccion(reproducciones: &[Reproduccion], canal: u8) -> Vec<u8> {
+    let mut respuesta = vec![0; 9];
+    if let Some(r) = reproducciones.iter().find(|r| r.canal == canal) {
+        respuesta[0] = 1;
+        respuesta[1..5].copy_from_slice(&r.vueltas.to_be_bytes());
+        respuesta[5..].copy_from_slice(&r.repeticiones.to_be_bytes());
+    }
+    respuesta
+}
+
+async fn proxima_muestra(reproducciones: &[Reproduccion]) {
+    match reproducciones.iter().map(|r| r.proxima).min() {
+        Some(proxima) => sleep_until(proxima).await,
+        None => std::future::pending().await,
+    }
+}
+
+//(canal, codigo) de las muestras que ya tienen que salir, quitando las formas terminadas
+fn muestras_vencidas(reproducciones: &mut Vec<Reproduccion>) -> Vec<(u8, u16)> {
+    let ahora = Instant::now();
+    let muestras = reproducciones
+        .iter_mut()
+        .filter(|r| r.proxima <= ahora)
+        .map(|r| (r.canal, r.avanzar()))
+        .collect();
+    reproducciones.retain(|r| !r.terminada());
+    muestras
+}
+
+//Lo que puede reproducir cada salida
+struct LimitesDac {
+    canales: usize,
+    maximo: u16,
+    tasa: u32,
+}
+
+//Responde las peticiones de formas de onda, devuelve las que no son
+fn reproduccion(
+    peticion: PeticionDac,
+    limites: &LimitesDac,
+    reproducciones: &mut Vec<Reproduccion>,
+    tx: &tokio::sync::broadcast::Sender<Vec<u8>>,
+    verbose: bool,
+) -> Option<PeticionDac> {
+    match peticion {
+        PeticionDac::Reproducir(reproduccion)
+            if reproduccion.canal as usize >= limites.canales
+                || reproduccion.tasa > limites.tasa
+                || reproduccion.muestras.iter().any(|m| *m > limites.maximo) =>
+        {
+            if verbose {
+                println!(
+                    "Dac waveform channel, code or rate out of range, at most {} samples per second",
+                    limites.tasa
+                );
+            }
+            tx.send(vec![0xF0, 0xF0]).unwrap();
+        }
+        PeticionDac::Reproducir(reproduccion) => {
+            if verbose {
+                println!(
+                    "Dac channel {} playing {} samples every {:?}",
+                    reproduccion.canal,
+                    reproduccion.muestras.len(),
+                    reproduccion.periodo
+                );
+            }
+            reproducciones.retain(|r| r.canal != reproduccion.canal);
+            reproducciones.push(reproduccion);
+            tx.send(vec![0, 0]).unwrap();
+        }
+        PeticionDac::Detener(canal) => {
+            let activa = reproducciones.iter().any(|r| r.canal == canal);
+            reproducciones.retain(|r| r.canal != canal);
+            if verbose && activa {
+                println!("Dac channel {} stopped", canal);
+            }
+            tx.send(vec![0, activa as u8]).unwrap();
+        }
+        PeticionDac::Reproduccion(canal) => {
+            tx.send(estado_reproduccion(reproducciones, canal)).unwrap();
+        }
+        peticion => return Some(peticion),
+    }
+    None
+}
+
+//Con que se generan las salidas del DAC
+pub enum SalidaDac {
+    Pwm(config::PwmDac),
+    Spi(DacChip, config::SpiDevice),
+    I2c(DacChip, config::Dac),
+}
 
 pub async fn dac_handler(
-    hat: bool,
     verbose: bool,
-    rx: tokio::sync::mpsc::Receiver<[u8; 3]>,
-    tx: tokio::sync::broadcast::Sender<[u8; 2]>,
+    rx: tokio::sync::mpsc::Receiver<PeticionDac>,
+    tx: tokio::sync::broadcast::Sender<Vec<u8>>,
+    seguro: tokio::sync::broadcast::Receiver<Listo>,
+    salida: SalidaDac,
+    traza: TrazaSpi,
 ) {
+    match salida {
+        SalidaDac::Pwm(config) => pwm_dac_handler(config, verbose, rx, tx, seguro).await,
+        SalidaDac::Spi(DacChip::Spi10, dispositivo) => {
+            let chip = Spi10::abrir(&dispositivo, traza, verbose);
+            chip_dac_handler(chip, verbose, rx, tx, seguro).await;
+        }
+        SalidaDac::Spi(chip, dispositivo) => {
+            let chip = Ad56x8::abrir(chip, &dispositivo, traza, verbose);
+            chip_dac_handler(chip, verbose, rx, tx, seguro).await;
+        }
+        SalidaDac::I2c(chip, config) => {
+            let chip = Dac7578::abrir(chip, &config, verbose);
+            chip_dac_handler(chip, verbose, rx, tx, seguro).await;
+        }
+    }
+}
+
+//Bits de los codigos
+pub fn bits(config: &Config, hat: bool) -> u8 {
     if hat {
-        pwm_dac_handler(verbose, rx, tx).await;
+        config.pwm_dac.bits
     } else {
-        spi_dac_handler(verbose, rx, tx).await;
+        config.dac.chip.bits()
     }
 }
 
-/* SPI CON EL DAC */
-async fn spi_dac_handler(
+/* DAC CON UN CHIP */
+async fn chip_dac_handler<D: DacDriver>(
+    mut chip: D,
     verbose: bool,
-    mut rx: tokio::sync::mpsc::Receiver<[u8; 3]>,
-    tx: tokio::sync::broadcast::Sender<[u8; 2]>,
+    mut rx: tokio::sync::mpsc::Receiver<PeticionDac>,
+    tx: tokio::sync::broadcast::Sender<Vec<u8>>,
+    mut seguro: tokio::sync::broadcast::Receiver<Listo>,
 ) {
-    let spi = Spi::new(Bus::Spi0, SlaveSelect::Ss1, 1000000, Mode::Mode0).expect("Falló abrir dac");
-    let mut buffer = [0; 3];
-    let mut respuesta = [0; 2];
+    let maximo = ((1u32 << chip.bits()) - 1) as u16;
+    let limites = LimitesDac {
+        canales: 8,
+        maximo,
+        tasa: chip.tasa_maxima(),
+    };
+    let mut canales = [CanalDac::default(); 8];
+    let mut reproducciones = Vec::new();
 
     loop {
-        let mut msg = rx.recv().await.unwrap();
+        let peticion = tokio::select! {
+            peticion = rx.recv() => peticion.unwrap(),
+            Ok(_listo) = seguro.recv() => {
+                if verbose {
+                    println!("Dac set to 0");
+                }
+                reproducciones.clear();
+                for canal in 0..8 {
+                    if let Err(e) = chip.escribir(canal, 0).await {
+                        println!("Dac channel {} not set to 0: {}", canal, e);
+                    }
+                }
+                canales = [CanalDac::default(); 8];
+                continue;
+            }
+            _ = proxima_muestra(&reproducciones) => {
+                for (canal, codigo) in muestras_vencidas(&mut reproducciones) {
+                    match chip.escribir(canal, codigo).await {
+                        Ok(_) => {
+                            canales[canal as usize] = CanalDac {
+                                codigo,
+                                apagado: false,
+                            };
+                        }
+                        Err(e) => {
+                            if verbose {
+                                println!("Dac channel {} failed: {}", canal, e);
+                            }
+                        }
+                    }
+                }
+                continue;
+            }
+        };
 
-        if msg[0] & 0xF0 > 0x70 {
-            if verbose {
-                println!("Address out of range");
+        let Some(peticion) = reproduccion(peticion, &limites, &mut reproducciones, &tx, verbose)
+        else {
+            continue;
+        };
+        let respuesta = match peticion {
+            //Escribir un canal detiene su forma de onda
+            PeticionDac::Escribir(canal, codigo) if canal <= 7 && codigo <= maximo => {
+                reproducciones.retain(|r| r.canal != canal);
+                let lectura = chip.escribir(canal, codigo).await;
+                if lectura.is_ok() {
+                    canales[canal as usize] = CanalDac {
+                        codigo,
+                        apagado: false,
+                    };
+                }
+                palabra_dac(lectura, verbose)
             }
-            tx.send([0xF0, 0xF0]).unwrap();
-        }
+            PeticionDac::Leer(canal) if canal <= 7 => palabra_dac(chip.leer(canal).await, verbose),
+            PeticionDac::Estado(pedidos) => {
+                let mut respuesta = Vec::with_capacity(pedidos.len() * 4);
+                for canal in pedidos {
+                    let lectura = chip.leer(canal).await.unwrap_or_else(|e| {
+                        if verbose {
+                            println!("Dac channel {} failed: {}", canal, e);
+                        }
+                        Lectura {
+                            codigo: 0,
+                            valido: false,
+                        }
+                    });
+                    let apagado = canales[canal as usize].apagado;
+                    respuesta
+                        .extend_from_slice(&EstadoDac::de_lectura(canal, lectura, apagado).bytes());
+                }
+                respuesta
+            }
+            PeticionDac::Varios(codigos) if codigos.iter().all(|(_, codigo)| *codigo <= maximo) => {
+                match chip.escribir_juntos(&codigos).await {
+                    Ok(lecturas) => {
+                        let mut respuesta = Vec::with_capacity(codigos.len() * 4);
+                        for ((canal, codigo), lectura) in codigos.into_iter().zip(lecturas) {
+                            reproducciones.retain(|r| r.canal != canal);
+                            canales[canal as usize] = CanalDac {
+                                codigo,
+                                apagado: false,
+                            };
+                            respuesta.extend_from_slice(
+                                &EstadoDac::de_lectura(canal, lectura, false).bytes(),
+                            );
+                        }
+                        respuesta
+                    }
+                    Err(e) => {
+                        if verbose {
+                            println!("Dac channels failed: {}", e);
+                        }
+                        vec![0xF0]
+                    }
+                }
+            }
+            PeticionDac::Varios(_) => {
+                if verbose {
+                    println!("Dac code out of range");
+                }
+                vec![0xF0]
+            }
+            PeticionDac::ConfigurarSpi(comando, valor) => match chip.spi() {
+                Some(dispositivo) => configurar(dispositivo, comando, valor, verbose),
+                None => {
+                    if verbose {
+                        println!("Dac chip is not on spi");
+                    }
+                    vec![0xF0]
+                }
+            },
+            _ => {
+                if verbose {
+                    println!("Address or code out of range");
+                }
+                vec![0xF0, 0xF0]
+            }
+        };
+        tx.send(respuesta).unwrap();
+    }
+}
 
-        spi.transfer(&mut buffer, &msg).unwrap();
-        if verbose {
-            println!("Spi sent: {:02X}{:02X}{:02X}", msg[0], msg[1], msg[2]);
-            println!(
-                "Spi got: {:02X}{:02X}{:02X}",
-                buffer[0], buffer[1], buffer[2]
-            );
-        }
-        sleep(Duration::from_millis(50));
-        if msg[0] & 0x0C == 0 {
-            msg[0] |= 0xC;
-            spi.transfer(&mut buffer, &msg).unwrap();
+//Lo que queda en el canal con el bit 15 en 1 si no es valido, 0xF0F0 si fallo
+fn palabra_dac(lectura: Result<Lectura, String>, verbose: bool) -> Vec<u8> {
+    match lectura {
+        Ok(Lectura { codigo, valido }) => (codigo | (!valido as u16) << 15).to_be_bytes().to_vec(),
+        Err(e) => {
             if verbose {
-                println!("Spi sent: {:02X}{:02X}{:02X}", msg[0], msg[1], msg[2]);
-                println!(
-                    "Spi got: {:02X}{:02X}{:02X}",
-                    buffer[0], buffer[1], buffer[2]
-                );
+                println!("Dac failed: {}", e);
             }
+            vec![0xF0, 0xF0]
         }
+    }
+}
 
-        respuesta.clone_from_slice(&buffer[1..]);
-        //000000dddddddddd <- Respuesta valida
-        //100000dddddddddd <- Respuesta invalida
-        respuesta[0] |= !buffer[0].reverse_bits();
-        tx.send(respuesta).unwrap();
+/* DAC CON PWM */
+enum Pwm {
+    Software(OutputPin),
+    Hardware(rppal::pwm::Pwm),
+}
+
+struct SalidaPwm {
+    pin: u8,
+    pwm: Pwm,
+}
+
+impl SalidaPwm {
+    //Si el canal por hardware no esta disponible queda por software
+    fn abrir(gpio: &Gpio, pin: u8, hardware: bool) -> Self {
+        let canal = match config::canal_pwm(pin) {
+            Some(0) => Channel::Pwm0,
+            _ => Channel::Pwm1,
+        };
+        let pwm = match hardware.then(|| rppal::pwm::Pwm::new(canal)) {
+            Some(Ok(pwm)) => Pwm::Hardware(pwm),
+            resultado => {
+                if let Some(Err(e)) = resultado {
+                    println!("No hardware pwm on gpio {}, using software: {}", pin, e);
+                }
+                let salida = gpio
+                    .get(pin)
+                    .unwrap_or_else(|e| panic!("Falló gettear el gpio {}: {}", pin, e))
+                    .into_output_low();
+                Pwm::Software(salida)
+            }
+        };
+        SalidaPwm { pin, pwm }
     }
+
+    //Devuelve el ciclo de trabajo logrado
+    fn fijar(&mut self, frecuencia: f64, ciclo: f64) -> Result<f64, String> {
+        match &mut self.pwm {
+            Pwm::Software(salida) if ciclo == 0.0 => {
+                salida.clear_pwm().map_err(|e| e.to_string())?;
+                salida.set_low();
+                Ok(0.0)
+            }
+            //rppal trunca el periodo y el pulso a nanosegundos
+            Pwm::Software(salida) => {
+                salida
+                    .set_pwm_frequency(frecuencia, ciclo)
+                    .map_err(|e| e.to_string())?;
+                let periodo = (1e9 / frecuencia).trunc();
+                Ok((periodo * ciclo).trunc() / periodo)
+            }
+            Pwm::Hardware(pwm) => pwm
+                .set_frequency(frecuencia, ciclo)
+                .and_then(|_| pwm.enable())
+                .and_then(|_| pwm.duty_cycle())
+                .map_err(|e| e.to_string()),
+        }
+    }
+}
+
+//Ciclo de trabajo en centesimos de porcentaje
+fn centesimos(ciclo: f64) -> Vec<u8> {
+    ((ciclo * 10000.0).round() as u16).to_be_bytes().to_vec()
 }
 
-/* FALSO DAC CON PWM */
 async fn pwm_dac_handler(
+    config: config::PwmDac,
     verbose: bool,
-    mut rx: tokio::sync::mpsc::Receiver<[u8; 3]>,
-    tx: tokio::sync::broadcast::Sender<[u8; 2]>,
+    mut rx: tokio::sync::mpsc::Receiver<PeticionDac>,
+    tx: tokio::sync::broadcast::Sender<Vec<u8>>,
+    mut seguro: tokio::sync::broadcast::Receiver<Listo>,
 ) {
     let gpio = Gpio::new().expect("Falló obtener gpios");
-    let (tx0, rx0) = tokio::sync::mpsc::channel(16);
-    let pin = gpio
-        .get(20)
-        .expect("Falló gettear el gpio 20")
-        .into_output();
-    tokio::spawn(async move {
-        pwm(rx0, pin).await;
-    });
-    let (tx1, rx1) = tokio::sync::mpsc::channel(16);
-    let pin = gpio
-        .get(21)
-        .expect("Falló gettear el gpio 21")
-        .into_output();
-    tokio::spawn(async move {
-        pwm(rx1, pin).await;
-    });
-    let (tx2, rx2) = tokio::sync::mpsc::channel(16);
-    let pin = gpio
-        .get(16)
-        .expect("Falló gettear el gpio 16")
-        .into_output();
-    tokio::spawn(async move {
-        pwm(rx2, pin).await;
-    });
-    let (tx3, rx3) = tokio::sync::mpsc::channel(16);
-    let pin = gpio
-        .get(19)
-        .expect("Falló gettear el gpio 19")
-        .into_output();
-    tokio::spawn(async move {
-        pwm(rx3, pin).await;
-    });
-    let (tx4, rx4) = tokio::sync::mpsc::channel(16);
-    let pin = gpio
-        .get(13)
-        .expect("Falló gettear el gpio 13")
-        .into_output();
-    tokio::spawn(async move {
-        pwm(rx4, pin).await;
-    });
-    let (tx5, rx5) = tokio::sync::mpsc::channel(16);
-    let pin = gpio.get(6).expect("Falló gettear el gpio 6").into_output();
-    tokio::spawn(async move {
-        pwm(rx5, pin).await;
-    });
-    let (tx6, rx6) = tokio::sync::mpsc::channel(16);
-    let pin = gpio.get(5).expect("Falló gettear el gpio 5").into_output();
-    tokio::spawn(async move {
-        pwm(rx6, pin).await;
-    });
-    let (tx7, rx7) = tokio::sync::mpsc::channel(16);
-    let pin = gpio
-        .get(26)
-        .expect("Falló gettear el gpio 26")
-        .into_output();
-    tokio::spawn(async move {
-        pwm(rx7, pin).await;
-    });
-    let mut respuesta = [0; 2];
+    let mut salidas: Vec<SalidaPwm> = config
+        .pins
+        .iter()
+        .map(|pin| SalidaPwm::abrir(&gpio, *pin, config.hardware_pins.contains(pin)))
+        .collect();
+    let frecuencia = config.frequency_hz;
+    let escala = (1u32 << config.bits) as f64;
+    let maximo = ((1u32 << config.bits) - 1) as u16;
+    let en_rango =
+        |canal: u8, codigo: u16| (canal as usize) < config.pins.len() && codigo <= maximo;
+    //Mas muestras que periodos del PWM no cambian la salida
+    let limites = LimitesDac {
+        canales: config.pins.len(),
+        maximo,
+        tasa: TASA_MAXIMA.min(frecuencia as u32).max(1),
+    };
+    let mut canales = [CanalDac::default(); 8];
+    //Ciclo de trabajo logrado en cada canal, lo que responde una lectura
+    let mut ciclos = [0.0; 8];
+    let mut reproducciones = Vec::new();
 
     loop {
-        let msg = rx.recv().await.unwrap();
-
-        if verbose {
-            println!("Dac got: {:02X}{:02X}{:02X}", msg[0], msg[1], msg[2]);
-        }
-
-        respuesta.clone_from_slice(&msg[1..]);
-
-        match msg[0] & 0xF {
-            0 => {
-                tx0.send(u16::from_be_bytes(respuesta)).await.unwrap();
-            }
-            1 => {
-                tx1.send(u16::from_be_bytes(respuesta)).await.unwrap();
+        let peticion = tokio::select! {
+            peticion = rx.recv() => peticion.unwrap(),
+            Ok(_listo) = seguro.recv() => {
+                if verbose {
+                    println!("Dac set to 0");
+                }
+                reproducciones.clear();
+                for salida in salidas.iter_mut() {
+                    if let Err(e) = salida.fijar(frecuencia, 0.0) {
+                        println!("Gpio {} not set to 0: {}", salida.pin, e);
+                    }
+                }
+                canales = [CanalDac::default(); 8];
+                ciclos = [0.0; 8];
+                continue;
             }
-            2 => {
-                tx2.send(u16::from_be_bytes(respuesta)).await.unwrap();
+            _ = proxima_muestra(&reproducciones) => {
+                for (canal, codigo) in muestras_vencidas(&mut reproducciones) {
+                    let Some(salida) = salidas.get_mut(canal as usize) else {
+                        continue;
+                    };
+                    match salida.fijar(frecuencia, codigo as f64 / escala) {
+                        Ok(ciclo) => {
+                            ciclos[canal as usize] = ciclo;
+                            canales[canal as usize] = CanalDac {
+                                codigo,
+                                apagado: false,
+                            };
+                        }
+                        Err(e) => {
+                            if verbose {
+                                println!("Gpio {} failed: {}", salida.pin, e);
+                            }
+                        }
+                    }
+                }
+                continue;
             }
-            3 => {
-                tx3.send(u16::from_be_bytes(respuesta)).await.unwrap();
+        };
+
+        let Some(peticion) = reproduccion(peticion, &limites, &mut reproducciones, &tx, verbose)
+        else {
+            continue;
+        };
+        let respuesta = match peticion {
+            //Responde el ciclo de trabajo logrado en centesimos de porcentaje
+            PeticionDac::Escribir(canal, codigo) if en_rango(canal, codigo) => {
+                if verbose {
+                    println!("Dac got: channel {} code {:03X}", canal, codigo);
+                }
+                reproducciones.retain(|r| r.canal != canal);
+                let salida = &mut salidas[canal as usize];
+                match salida.fijar(frecuencia, codigo as f64 / escala) {
+                    Ok(ciclo) => {
+                        if verbose {
+                            println!("Gpio {} duty cycle {:.4}", salida.pin, ciclo);
+                        }
+                        ciclos[canal as usize] = ciclo;
+                        canales[canal as usize] = CanalDac {
+                            codigo,
+                            apagado: false,
+                        };
+                        centesimos(ciclo)
+                    }
+                    Err(e) => {
+                        if verbose {
+                            println!("Gpio {} failed: {}", salida.pin, e);
+                        }
+                        vec![0xF0, 0xF0]
+                    }
+                }
             }
-            4 => {
-                tx4.send(u16::from_be_bytes(respuesta)).await.unwrap();
+            //Responde el ciclo de trabajo guardado, sin tocar la salida
+            PeticionDac::Leer(canal) if en_rango(canal, 0) => centesimos(ciclos[canal as usize]),
+            PeticionDac::Estado(pedidos) => {
+                let mut respuesta = Vec::with_capacity(pedidos.len() * 4);
+                for canal in pedidos {
+                    let CanalDac { codigo, apagado } = canales[canal as usize];
+                    let estado = EstadoDac {
+                        canal,
+                        codigo,
+                        valido: en_rango(canal, 0),
+                        apagado,
+                    };
+                    respuesta.extend_from_slice(&estado.bytes());
+                }
+                respuesta
             }
-            5 => {
-                tx5.send(u16::from_be_bytes(respuesta)).await.unwrap();
+            //Las salidas PWM no tienen como actualizarse juntas, se escriben seguidas
+            PeticionDac::Varios(codigos)
+                if codigos.iter().all(|(c, codigo)| en_rango(*c, *codigo)) =>
+            {
+                let mut respuesta = Vec::with_capacity(codigos.len() * 4);
+                for (canal, codigo) in codigos {
+                    let salida = &mut salidas[canal as usize];
+                    match salida.fijar(frecuencia, codigo as f64 / escala) {
+                        Ok(ciclo) => ciclos[canal as usize] = ciclo,
+                        Err(e) => {
+                            if verbose {
+                                println!("Gpio {} failed: {}", salida.pin, e);
+                            }
+                            respuesta = vec![0xF0];
+                            break;
+                        }
+                    }
+                    reproducciones.retain(|r| r.canal != canal);
+                    canales[canal as usize] = CanalDac {
+                        codigo,
+                        apagado: false,
+                    };
+                    let estado = EstadoDac {
+                        canal,
+                        codigo,
+                        valido: true,
+                        apagado: false,
+                    };
+                    respuesta.extend_from_slice(&estado.bytes());
+                }
+                respuesta
             }
-            6 => {
-                tx6.send(u16::from_be_bytes(respuesta)).await.unwrap();
+            PeticionDac::Varios(_) => {
+                if verbose {
+                    println!("Dac channel or code out of range");
+                }
+                vec![0xF0]
             }
-            7 => {
-                tx7.send(u16::from_be_bytes(respuesta)).await.unwrap();
+            PeticionDac::ConfigurarSpi(..) => {
+                if verbose {
+                    println!("Dac is pwm, not spi");
+                }
+                vec![0xF0]
             }
             _ => {
                 if verbose {
-                    println!("Address out of range");
+                    println!("Address or code out of range");
                 }
-                respuesta = [0xF0, 0xF0];
+                vec![0xF0, 0xF0]
             }
-        }
-
+        };
         tx.send(respuesta).unwrap();
     }
 }
 
-async fn pwm(mut rx: tokio::sync::mpsc::Receiver<u16>, mut pin: rppal::gpio::OutputPin) {
-    loop {
-        let duty = rx.recv().await.unwrap();
-        pin.set_pwm_frequency(10000.0, duty as f64 / 1024.0)
-            .unwrap();
-        if duty == 0 {
-            pin.clear_pwm().unwrap();
-        }
-    }
-}
-
 pub async fn dac_read(
     msg: u32,
-    rx: &mut tokio::sync::broadcast::Receiver<[u8; 2]>,
-    tx: &tokio::sync::mpsc::Sender<[u8; 3]>,
-    hat: bool,
+    rx: &mut tokio::sync::broadcast::Receiver<Vec<u8>>,
+    tx: &tokio::sync::mpsc::Sender<PeticionDac>,
 ) -> [u8; 2] {
-    if hat {
-        analog_core(msg, rx, tx).await
-    } else {
-        dac_core(0xC, msg, rx, tx).await
-    }
+    dac_core(PeticionDac::Leer(canal(msg)), rx, tx).await
 }
 
 pub async fn dac_write(
     msg: u32,
-    rx: &mut tokio::sync::broadcast::Receiver<[u8; 2]>,
-    tx: &tokio::sync::mpsc::Sender<[u8; 3]>,
-    hat: bool,
+    rx: &mut tokio::sync::broadcast::Receiver<Vec<u8>>,
+    tx: &tokio::sync::mpsc::Sender<PeticionDac>,
 ) -> [u8; 2] {
-    if hat {
-        analog_core(msg, rx, tx).await
+    let codigo = (msg & 0xFFFF) as u16;
+    dac_core(PeticionDac::Escribir(canal(msg), codigo), rx, tx).await
+}
+
+//msg: [opcode, todos << 4 | canal]
+//Respuesta: por canal [canal, codigo (u16), estado], estado bit 0: valido, bit 1: apagado
+pub async fn dac_status(
+    msg: u32,
+    rx: &mut tokio::sync::broadcast::Receiver<Vec<u8>>,
+    tx: &tokio::sync::mpsc::Sender<PeticionDac>,
+) -> Vec<u8> {
+    let pedidos = if msg & 0x00100000 != 0 {
+        (0..8).collect()
     } else {
-        dac_core(0x0, msg, rx, tx).await
+        let canal = ((msg >> 16) & 0x0F) as u8;
+        if canal > 7 {
+            return vec![0xF0];
+        }
+        vec![canal]
+    };
+
+    tx.send(PeticionDac::Estado(pedidos)).await.unwrap();
+
+    rx.recv().await.unwrap()
+}
+
+//carga: [canal, codigo (u16)] por cada canal a cambiar, los codigos los revisa el DAC
+//Respuesta: por canal [canal, codigo (u16), estado], como la de dac_status
+pub async fn dac_write_channels(
+    carga: Vec<u8>,
+    rx: &mut tokio::sync::broadcast::Receiver<Vec<u8>>,
+    tx: &tokio::sync::mpsc::Sender<PeticionDac>,
+    verbose: bool,
+) -> Vec<u8> {
+    let mut codigos: Vec<(u8, u16)> = Vec::new();
+    let mut valido = !carga.is_empty() && carga.len().is_multiple_of(3);
+    for c in carga.chunks_exact(3) {
+        let codigo = u16::from_be_bytes([c[1], c[2]]);
+        valido &= c[0] <= 7 && !codigos.iter().any(|(canal, _)| *canal == c[0]);
+        codigos.push((c[0], codigo));
+    }
+    if !valido {
+        if verbose {
+            println!("Invalid dac channels or codes");
+        }
+        return vec![0xF0];
     }
+
+    tx.send(PeticionDac::Varios(codigos)).await.unwrap();
+
+    rx.recv().await.unwrap()
 }
 
-async fn dac_core(
-    wr: u8,
+//msg: [opcode, comando << 4 | canal, largo], comando 0: reproducir la forma de onda de la
+//carga, 1: detener
+pub async fn dac_waveform(
     msg: u32,
-    rx: &mut tokio::sync::broadcast::Receiver<[u8; 2]>,
-    tx: &tokio::sync::mpsc::Sender<[u8; 3]>,
+    carga: Vec<u8>,
+    rx: &mut tokio::sync::broadcast::Receiver<Vec<u8>>,
+    tx: &tokio::sync::mpsc::Sender<PeticionDac>,
+    verbose: bool,
 ) -> [u8; 2] {
-    let mut arr = [0; 3];
-    let msg = msg & 0x000F03FF;
-    arr.clone_from_slice(&msg.to_be_bytes()[1..]);
-    arr[1] <<= 4;
-    arr[1] |= wr;
+    let canal = ((msg >> 16) & 0x0F) as u8;
+    let peticion = match (msg >> 20) & 0x0F {
+        0 => match Reproduccion::de_carga(canal, &carga) {
+            Ok(reproduccion) => PeticionDac::Reproducir(reproduccion),
+            Err(e) => {
+                if verbose {
+                    println!("Dac waveform not played: {}", e);
+                }
+                return [0xF0, 0xF0];
+            }
+        },
+        1 if canal <= 7 => PeticionDac::Detener(canal),
+        _ => return [0xF0, 0xF0],
+    };
+
+    tx.send(peticion).await.unwrap();
 
-    tx.send(arr).await.unwrap();
+    let respuesta = rx.recv().await.unwrap();
+    [respuesta[0], respuesta[1]]
+}
+
+//comando 2: estado, [activa, vueltas hechas (u32), repeticiones (u32)]
+pub async fn dac_waveform_status(
+    msg: u32,
+    rx: &mut tokio::sync::broadcast::Receiver<Vec<u8>>,
+    tx: &tokio::sync::mpsc::Sender<PeticionDac>,
+) -> Vec<u8> {
+    let canal = ((msg >> 16) & 0x0F) as u8;
+    if canal > 7 {
+        return vec![0xF0];
+    }
+
+    tx.send(PeticionDac::Reproduccion(canal)).await.unwrap();
 
     rx.recv().await.unwrap()
 }
 
-async fn analog_core(
+//msg: como el de spi_config, para el dispositivo dac que usa el handler del DAC
+pub async fn dac_spi_config(
     msg: u32,
-    rx: &mut tokio::sync::broadcast::Receiver<[u8; 2]>,
-    tx: &tokio::sync::mpsc::Sender<[u8; 3]>,
+    rx: &mut tokio::sync::broadcast::Receiver<Vec<u8>>,
+    tx: &tokio::sync::mpsc::Sender<PeticionDac>,
+) -> Vec<u8> {
+    let comando = ((msg >> 16) & 0x0F) as u8;
+    tx.send(PeticionDac::ConfigurarSpi(comando, (msg & 0xFFFF) as u16))
+        .await
+        .unwrap();
+    rx.recv().await.unwrap()
+}
+
+fn canal(msg: u32) -> u8 {
+    ((msg >> 16) & 0x0F) as u8
+}
+
+async fn dac_core(
+    peticion: PeticionDac,
+    rx: &mut tokio::sync::broadcast::Receiver<Vec<u8>>,
+    tx: &tokio::sync::mpsc::Sender<PeticionDac>,
 ) -> [u8; 2] {
-    let mut arr = [0; 3];
-    let msg = msg & 0x000F03FF;
-    arr.clone_from_slice(&msg.to_be_bytes()[1..]);
+    tx.send(peticion).await.unwrap();
 
-    tx.send(arr).await.unwrap();
+    let respuesta = rx.recv().await.unwrap();
+    [respuesta[0], respuesta[1]]
+}
 
-    rx.recv().await.unwrap()
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn carga(forma: u8, tasa: u32, repeticiones: u32, parametros: &[u16]) -> Vec<u8> {
+        let mut carga = vec![forma];
+        carga.extend_from_slice(&tasa.to_be_bytes());
+        carga.extend_from_slice(&repeticiones.to_be_bytes());
+        for parametro in parametros {
+            carga.extend_from_slice(&parametro.to_be_bytes());
+        }
+        carga
+    }
+
+    fn muestras(forma: u8, parametros: &[u16]) -> Vec<u16> {
+        Reproduccion::de_carga(0, &carga(forma, 100, 0, parametros))
+            .unwrap()
+            .muestras
+    }
+
+    #[test]
+    fn formas() {
+        assert_eq!(muestras(0, &[0, 300, 4]), [0, 100, 200, 300]);
+        assert_eq!(muestras(1, &[0, 200, 4]), [100, 200, 100, 0]);
+        assert_eq!(muestras(2, &[10, 20, 4]), [20, 20, 10, 10]);
+        assert_eq!(muestras(3, &[7, 1, 0xFFFF]), [7, 1, 0xFFFF]);
+        assert_eq!(muestras(0, &[5, 5, 2]), [5, 5]);
+    }
+
+    #[test]
+    fn tasa_y_repeticiones() {
+        let reproduccion = Reproduccion::de_carga(7, &carga(3, TASA_MAXIMA, 3, &[1])).unwrap();
+        assert_eq!(reproduccion.canal, 7);
+        assert_eq!(reproduccion.tasa, TASA_MAXIMA);
+        assert_eq!(reproduccion.periodo, Duration::from_millis(1));
+        assert_eq!(reproduccion.repeticiones, 3);
+    }
+
+    #[test]
+    fn tasa_fuera_de_rango() {
+        for tasa in [0, TASA_MAXIMA + 1, u32::MAX] {
+            assert_eq!(
+                Reproduccion::de_carga(0, &carga(0, tasa, 0, &[0, 10, 2])).unwrap_err(),
+                format!("rate {} out of range", tasa)
+            );
+        }
+    }
+
+    #[test]
+    fn canal_fuera_de_rango() {
+        assert_eq!(
+            Reproduccion::de_carga(8, &carga(0, 100, 0, &[0, 10, 2])).unwrap_err(),
+            "channel 8 out of range"
+        );
+    }
+
+    #[test]
+    fn carga_corta() {
+        let carga = carga(0, 100, 0, &[]);
+        for largo in [0, 1, 8] {
+            assert_eq!(
+                Reproduccion::de_carga(0, &carga[..largo]).unwrap_err(),
+                "missing waveform parameters"
+            );
+        }
+    }
+
+    #[test]
+    fn formas_invalidas() {
+        for (forma, parametros) in [
+            (0, &[0, 10, 1][..]),
+            (1, &[10, 0, 4]),
+            (2, &[0, 10]),
+            (0, &[0, 10, 4, 1]),
+            (3, &[]),
+            (4, &[0, 10, 4]),
+        ] {
+            assert_eq!(
+                Reproduccion::de_carga(0, &carga(forma, 100, 0, parametros)).unwrap_err(),
+                "invalid waveform"
+            );
+        }
+    }
+
+    #[test]
+    fn ciclo_de_trabajo_en_centesimos() {
+        assert_eq!(centesimos(0.5), vec![0x13, 0x88]);
+        assert_eq!(centesimos(0.0), vec![0x00, 0x00]);
+        assert_eq!(centesimos(1023.0 / 1024.0), 9990u16.to_be_bytes().to_vec());
+    }
 }
@@ -0,0 +1,345 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+use crate::config;
+
+//Direccion en bytes -> byte
+pub type Imagen = BTreeMap<u32, u8>;
+
+pub type FirmwareSubido = Arc<Mutex<Firmware>>;
+
+#[derive(Default, Clone, Copy, PartialEq)]
+enum Formato {
+    #[default]
+    Hex = 0,
+    Binario = 1,
+}
+
+/* IMAGEN DE FIRMWARE RECIBIDA POR PARTES */
+#[derive(Default)]
+pub struct Firmware {
+    carga: Vec<u8>,
+    formato: Formato,
+    //Solo despues de validar la carga completa
+    imagen: Option<Imagen>,
+    mensaje: String,
+}
+
+impl Firmware {
+    pub fn imagen(&self) -> Option<&Imagen> {
+        self.imagen.as_ref()
+    }
+
+    pub fn recibidos(&self) -> usize {
+        self.carga.len()
+    }
+
+    //Parsea y valida lo recibido, la imagen queda solo si es valida
+    pub fn comprobar(&mut self, config: &config::Firmware) -> Result<(), String> {
+        let resultado = match self.formato {
+            Formato::Hex => parsear_hex(&self.carga),
+            Formato::Binario => Ok(parsear_binario(&self.carga, config.binary_address)),
+        }
+        .and_then(|imagen| validar(&imagen, &config.region).map(|_| imagen));
+        match resultado {
+            Ok(imagen) => {
+                self.mensaje = format!("{} bytes valid", imagen.len());
+                self.imagen = Some(imagen);
+                Ok(())
+            }
+            Err(e) => {
+                self.mensaje = e.clone();
+                self.imagen = None;
+                Err(e)
+            }
+        }
+    }
+}
+
+/* INTEL HEX */
+pub fn parsear_hex(texto: &[u8]) -> Result<Imagen, String> {
+    let texto = std::str::from_utf8(texto).map_err(|_| String::from("HEX file is not text"))?;
+    let mut imagen = Imagen::new();
+    let mut base: u32 = 0;
+    let mut terminado = false;
+
+    for (n, linea) in texto
+        .lines()
+        .enumerate()
+        .filter(|(_, l)| !l.trim().is_empty())
+    {
+        let registro = linea
+            .trim()
+            .strip_prefix(':')
+            .filter(|r| r.len() >= 10 && r.len().is_multiple_of(2))
+            .and_then(|r| {
+                (0..r.len())
+                    .step_by(2)
+                    .map(|i| u8::from_str_radix(&r[i..i + 2], 16).ok())
+                    .collect::<Option<Vec<u8>>>()
+            })
+            .ok_or(format!("line {}: invalid record", n + 1))?;
+        if registro.len() != registro[0] as usize + 5 {
+            return Err(format!("line {}: wrong length", n + 1));
+        }
+        if registro.iter().fold(0u8, |s, b| s.wrapping_add(*b)) != 0 {
+            return Err(format!("line {}: wrong checksum", n + 1));
+        }
+        let direccion = u16::from_be_bytes([registro[1], registro[2]]) as u32;
+        let datos = &registro[4..registro.len() - 1];
+        match registro[3] {
+            0 => {
+                for (i, byte) in datos.iter().enumerate() {
+                    let direccion = base.wrapping_add(direccion + i as u32);
+                    if imagen.insert(direccion, *byte).is_some_and(|b| b != *byte) {
+                        return Err(format!(
+                            "line {}: address 0x{:X} written twice",
+                            n + 1,
+                            direccion
+                        ));
+                    }
+                }
+            }
+            1 => {
+                terminado = true;
+                break;
+            }
+            2 if datos.len() == 2 => base = (u16::from_be_bytes([datos[0], datos[1]]) as u32) << 4,
+            4 if datos.len() == 2 => base = (u16::from_be_bytes([datos[0], datos[1]]) as u32) << 16,
+            3 | 5 => {}
+            tipo => return Err(format!("line {}: unsupported record type {}", n + 1, tipo)),
+        }
+    }
+    if !terminado {
+        return Err(String::from("missing end of file record"));
+    }
+    Ok(imagen)
+}
+
+/* BINARIO CRUDO, A PARTIR DE UNA DIRECCION */
+pub fn parsear_binario(datos: &[u8], base: u32) -> Imagen {
+    datos
+        .iter()
+        .enumerate()
+        .map(|(i, byte)| (base.wrapping_add(i as u32), *byte))
+        .collect()
+}
+
+//Cada byte tiene que caer en alguna region del mapa de memoria
+pub fn validar(imagen: &Imagen, regiones: &[config::MemoryRegion]) -> Result<(), String> {
+    if imagen.is_empty() {
+        return Err(String::from("empty image"));
+    }
+    for direccion in imagen.keys() {
+        if !regiones.iter().any(|r| r.contiene(*direccion)) {
+            return Err(format!("address 0x{:X} out of the memory map", direccion));
+        }
+    }
+    Ok(())
+}
+
+//CRC-32 de los bytes en orden de direccion, para comparar con el archivo original
+fn crc32(imagen: &Imagen) -> u32 {
+    let mut crc = 0xFFFFFFFFu32;
+    for byte in imagen.values() {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB88320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+//msg: [opcode, comando << 4 | formato, largo], comando 0: empezar una imagen nueva,
+//1: seguir recibiendola. Formato 0: Intel HEX, 1: binario
+pub fn firmware_upload(
+    msg: u32,
+    carga: Vec<u8>,
+    firmware: &FirmwareSubido,
+    config: &config::Firmware,
+    verbose: bool,
+) -> [u8; 2] {
+    let mut firmware = firmware.lock().unwrap();
+    let formato = match (msg >> 16) & 0x0F {
+        0 => Formato::Hex,
+        1 => Formato::Binario,
+        _ => return [0xF0, 0xF0],
+    };
+    match (msg >> 20) & 0x0F {
+        0 => {
+            *firmware = Firmware::default();
+            firmware.formato = formato;
+        }
+        1 => firmware.imagen = None,
+        _ => return [0xF0, 0xF0],
+    }
+    if firmware.carga.len() + carga.len() > config.max_size {
+        if verbose {
+            println!("Firmware image larger than {} bytes", config.max_size);
+        }
+        firmware.mensaje = format!("image larger than {} bytes", config.max_size);
+        return [0xF0, 0xF0];
+    }
+    firmware.carga.extend_from_slice(&carga);
+    firmware.mensaje = format!("{} bytes received", firmware.carga.len());
+    [0, 0]
+}
+
+//comando 2: validar lo recibido, 3: consultar
+//Respuesta: [estado, formato, bytes de la imagen (u32), primera direccion (u32),
+//ultima direccion (u32), CRC-32 (u32), mensaje], estado 0xF0 si no hay imagen valida
+pub fn firmware_check(
+    msg: u32,
+    firmware: &FirmwareSubido,
+    config: &config::Firmware,
+    verbose: bool,
+) -> Vec<u8> {
+    let mut firmware = firmware.lock().unwrap();
+    if (msg >> 20) & 0x0F == 2 {
+        let _ = firmware.comprobar(config);
+        if verbose {
+            println!("Firmware image: {}", firmware.mensaje);
+        }
+    }
+
+    let mut respuesta = vec![if firmware.imagen.is_some() { 0 } else { 0xF0 }];
+    respuesta.push(firmware.formato as u8);
+    match &firmware.imagen {
+        Some(imagen) => {
+            respuesta.extend_from_slice(&(imagen.len() as u32).to_be_bytes());
+            respuesta.extend_from_slice(&imagen.keys().next().unwrap().to_be_bytes());
+            respuesta.extend_from_slice(&imagen.keys().next_back().unwrap().to_be_bytes());
+            respuesta.extend_from_slice(&crc32(imagen).to_be_bytes());
+        }
+        None => respuesta.extend_from_slice(&[0; 16]),
+    }
+    respuesta.extend_from_slice(firmware.mensaje.as_bytes());
+    respuesta
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn region(start: u32, size: u32) -> config::MemoryRegion {
+        config::MemoryRegion {
+            name: String::from("flash"),
+            start,
+            size,
+        }
+    }
+
+    #[test]
+    fn hex_valido() {
+        let imagen =
+            parsear_hex(b":0400100001020304E2\n:020000040001F9\n:02000000AABB99\n:00000001FF\n")
+                .unwrap();
+        let esperada: Imagen = [
+            (0x10, 1),
+            (0x11, 2),
+            (0x12, 3),
+            (0x13, 4),
+            (0x10000, 0xAA),
+            (0x10001, 0xBB),
+        ]
+        .into_iter()
+        .collect();
+        assert_eq!(imagen, esperada);
+    }
+
+    #[test]
+    fn hex_ignora_lo_que_sigue_al_fin() {
+        let imagen = parsear_hex(b":0100000055AA\n:00000001FF\nbasura\n").unwrap();
+        assert_eq!(imagen.len(), 1);
+    }
+
+    #[test]
+    fn hex_checksum_erroneo() {
+        let error = parsear_hex(b":0400100001020304E3\n:00000001FF\n").unwrap_err();
+        assert_eq!(error, "line 1: wrong checksum");
+    }
+
+    #[test]
+    fn hex_registros_malformados() {
+        for texto in [
+            &b"0400100001020304E2\n:00000001FF\n"[..],
+            b":04001000010203GGE2\n:00000001FF\n",
+            b":0400100001020304E\n:00000001FF\n",
+            b":00000001\n",
+        ] {
+            assert_eq!(parsear_hex(texto).unwrap_err(), "line 1: invalid record");
+        }
+        assert_eq!(
+            parsear_hex(b":0500100001020304E1\n:00000001FF\n").unwrap_err(),
+            "line 1: wrong length"
+        );
+        assert_eq!(
+            parsear_hex(b":00000006FA\n:00000001FF\n").unwrap_err(),
+            "line 1: unsupported record type 6"
+        );
+        assert_eq!(
+            parsear_hex(&[0xFF, 0xFE]).unwrap_err(),
+            "HEX file is not text"
+        );
+    }
+
+    #[test]
+    fn hex_sin_fin() {
+        let error = parsear_hex(b":0400100001020304E2\n").unwrap_err();
+        assert_eq!(error, "missing end of file record");
+    }
+
+    #[test]
+    fn hex_direccion_repetida() {
+        let texto = b":0100000055AA\n:010000006699\n:00000001FF\n";
+        assert_eq!(
+            parsear_hex(texto).unwrap_err(),
+            "line 2: address 0x0 written twice"
+        );
+        //El mismo valor dos veces no es un error
+        let texto = b":0100000055AA\n:0100000055AA\n:00000001FF\n";
+        assert!(parsear_hex(texto).is_ok());
+    }
+
+    #[test]
+    fn binario_desde_la_base() {
+        let imagen = parsear_binario(&[1, 2, 3], 0x100);
+        assert_eq!(
+            imagen.keys().copied().collect::<Vec<u32>>(),
+            [0x100, 0x101, 0x102]
+        );
+    }
+
+    #[test]
+    fn validar_regiones() {
+        let regiones = [region(0, 0x100), region(0x1000, 0x10)];
+        assert!(validar(&parsear_binario(&[0; 0x100], 0), &regiones).is_ok());
+        assert!(validar(&parsear_binario(&[0; 0x10], 0x1000), &regiones).is_ok());
+        assert_eq!(
+            validar(&parsear_binario(&[0; 0x11], 0x1000), &regiones).unwrap_err(),
+            "address 0x1010 out of the memory map"
+        );
+        assert_eq!(
+            validar(&parsear_binario(&[0; 2], 0xFF), &regiones).unwrap_err(),
+            "address 0x100 out of the memory map"
+        );
+    }
+
+    #[test]
+    fn validar_imagen_vacia() {
+        assert_eq!(
+            validar(&Imagen::new(), &[region(0, 0x100)]).unwrap_err(),
+            "empty image"
+        );
+    }
+
+    #[test]
+    fn crc32_conocido() {
+        assert_eq!(crc32(&parsear_binario(b"123456789", 0)), 0xCBF43926);
+        assert_eq!(crc32(&Imagen::new()), 0);
+    }
+}
@@ -1,5 +1,7 @@
 use std::env;
 use std::process::Command;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use tokio::sync::{broadcast, mpsc};
 
 extern crate unicode_segmentation;
@@ -15,10 +17,10 @@ mod spi;
 use spi::spi_handler;
 
 mod dac;
-use dac::dac_handler;
+use dac::{dac_handler, SalidaDac};
 
 mod server;
-use server::run;
+use server::{run, Canales};
 
 mod relay;
 use relay::relay_handler;
@@ -26,6 +28,30 @@ use relay::relay_handler;
 mod tnr_monitor;
 use tnr_monitor::monitor_handler;
 
+mod config;
+
+mod seguro;
+use seguro::{esperar_señal_de_apagado, estado_seguro};
+
+mod watchdog;
+
+mod secuencia;
+
+mod traza;
+
+mod registros;
+
+mod programador;
+
+mod firmware;
+
+mod calibracion;
+
+mod persistencia;
+
+mod controlador_dac;
+use traza::Traza;
+
 #[tokio::main]
 async fn main() {
     let args: Vec<String> = env::args().collect();
@@ -37,6 +63,7 @@ async fn main() {
     let mut little_endian = false;
     let mut hat = false;
     let mut mega_hertz = false;
+    let mut config_path = None;
 
     let mut arg = args.iter().peekable();
     arg.next();
@@ -73,6 +100,14 @@ async fn main() {
                     None => port,
                 }
             }
+            "-c" | "--config" => match arg.next() {
+                Some(path) => config_path = Some(path.as_str()),
+                None => {
+                    println!("Missing config file path");
+                    quit = true;
+                    break;
+                }
+            },
             "-V" | "--version" => {
                 println!("{}", VERSION);
                 quit = true;
@@ -90,7 +125,43 @@ async fn main() {
         verbose = false;
     }
 
-    if !quit {
+    let config = if quit {
+        None
+    } else {
+        match config::cargar(config_path) {
+            Ok(config) => Some(config),
+            Err(e) => {
+                println!("{}", e);
+                None
+            }
+        }
+    };
+
+    if let Some(mut config) = config {
+        let registros = match registros::cargar(&config.register_map.file, &config.register_dump) {
+            Ok(registros) => Arc::new(registros),
+            Err(e) => {
+                println!("{}", e);
+                return;
+            }
+        };
+        if mega_hertz {
+            if let Some(pic) = config.spi_device.iter_mut().find(|d| d.name == "pic") {
+                pic.clock_hz = 1000000;
+            }
+        }
+        let dac_device = config.spi_device.iter().find(|d| d.name == "dac").cloned();
+        let salida_dac = if hat {
+            SalidaDac::Pwm(config.pwm_dac.clone())
+        } else if !config.dac.chip.spi() {
+            SalidaDac::I2c(config.dac.chip, config.dac.clone())
+        } else if let Some(dac_device) = dac_device {
+            SalidaDac::Spi(config.dac.chip, dac_device)
+        } else {
+            println!("Invalid config: missing spi device dac, needed without --hat");
+            return;
+        };
+
         let (spi_tx, rx_spi) = mpsc::channel(16);
         let (tx_spi, spi_rx) = broadcast::channel(16);
 
@@ -100,59 +171,88 @@ async fn main() {
         let (tnr_tx, rx_tnr) = mpsc::channel(16);
         let (tx_tnr, tnr_rx) = broadcast::channel(16);
 
-        let (reset_relay_tx, rx_reset_relay) = mpsc::channel(16);
-        let (tx_reset_relay, reset_relay_rx) = broadcast::channel(16);
-
-        let (program_relay_tx, rx_program_relay) = mpsc::channel(16);
-        let (tx_program_relay, program_relay_rx) = broadcast::channel(16);
+        let (relay_tx, rx_relay) = mpsc::channel(16);
 
         let (monitor_tx, rx_monitor) = mpsc::channel(16);
         let (tx_monitor, monitor_rx) = broadcast::channel(16);
 
-        tokio::spawn(async move {
-            spi_handler(verbose, rx_spi, tx_spi, mega_hertz).await;
-        });
+        let (seguro_tx, _) = broadcast::channel(1);
+        let (apagado_tx, apagado_rx) = broadcast::channel(1);
+
+        let traza = Arc::new(Mutex::new(Traza::new(&config.spi_trace)));
 
+        let spi_devices = config.spi_device.clone();
+        let spi_traza = traza.clone();
         tokio::spawn(async move {
-            dac_handler(hat, verbose, rx_dac, tx_dac).await;
+            spi_handler(verbose, rx_spi, tx_spi, spi_devices, spi_traza).await;
         });
 
+        let seguro_rx = seguro_tx.subscribe();
+        let dac_traza = traza.clone();
         tokio::spawn(async move {
-            tnr_handler(verbose, rx_tnr, tx_tnr).await;
+            dac_handler(verbose, rx_dac, tx_dac, seguro_rx, salida_dac, dac_traza).await;
         });
 
+        let seguro_rx = seguro_tx.subscribe();
         tokio::spawn(async move {
-            relay_handler(verbose, rx_reset_relay, tx_reset_relay, 12).await;
+            tnr_handler(verbose, rx_tnr, tx_tnr, seguro_rx).await;
         });
 
+        let seguro_rx = seguro_tx.subscribe();
+        let relays = config.relay.clone();
+        let relay_bank = config.relay_bank.clone();
         tokio::spawn(async move {
-            relay_handler(verbose, rx_program_relay, tx_program_relay, 0).await;
+            relay_handler(verbose, rx_relay, relays, relay_bank, seguro_rx).await;
         });
 
         tokio::spawn(async move {
             monitor_handler(verbose, rx_monitor, tx_monitor).await;
         });
 
-        run(
-            verbose,
-            quiet,
-            port,
+        let canales = Canales {
             spi_rx,
             spi_tx,
             dac_rx,
             dac_tx,
             tnr_rx,
             tnr_tx,
-            reset_relay_rx,
-            reset_relay_tx,
-            program_relay_rx,
-            program_relay_tx,
+            relay_tx,
             monitor_rx,
             monitor_tx,
-            little_endian,
-            hat,
-        )
-        .await;
+            apagado_rx,
+            seguro_tx,
+            exclusion: Arc::new(tokio::sync::RwLock::new(())),
+            traza,
+            programador: Arc::new(Mutex::new(programador::Programador::default())),
+            firmware: Arc::new(Mutex::new(firmware::Firmware::default())),
+            calibracion: Arc::new(Mutex::new(calibracion::Calibracion::new(
+                &config.dac,
+                dac::bits(&config, hat),
+                verbose,
+            ))),
+            registros,
+        };
+        let timeout_seguro = Duration::from_millis(config.safe_state.timeout_ms);
+
+        tokio::select! {
+            _ = run(
+                verbose,
+                quiet,
+                port,
+                canales.resubscribe(),
+                little_endian,
+                hat,
+                Arc::new(config),
+            ) => {}
+            _ = esperar_señal_de_apagado() => {
+                if !quiet {
+                    println!("Shutting down");
+                }
+            }
+        }
+
+        let _ = apagado_tx.send(());
+        estado_seguro(&canales.seguro_tx, timeout_seguro, verbose).await;
     }
 }
 
@@ -172,6 +272,7 @@ fn imprimir_ayuda() {
     println!("\t-H --hat\t\tChange dac functionality to use software pwm as analog out");
     println!("\t-M --mega-hertz\t\tChange spi clock frequency, from 100kHz to 1MHz");
     println!("\t-p --port\t\tEspecify a port for the TCP server to listen at, 8000 by default");
+    println!("\t-c --config\t\tRead configuration from the given file");
     println!("\t-V --version\t\tPrints version information and exit");
     println!();
     println!("NOTE: you can uninstall the program at any time running:");
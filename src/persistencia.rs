@@ -0,0 +1,40 @@
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::fs;
+use std::path::Path;
+
+/* ESTADO GUARDADO EN ARCHIVOS TOML ENTRE REINICIOS */
+//Sin archivo o con uno invalido queda el valor por defecto
+pub fn cargar<T: DeserializeOwned + Default>(path: &str, verbose: bool) -> T {
+    match fs::read_to_string(path).map(|c| toml::from_str(&c)) {
+        Ok(Ok(guardado)) => guardado,
+        Ok(Err(e)) => {
+            println!("Ignoring invalid file {}: {}", path, e);
+            T::default()
+        }
+        Err(_) => {
+            if verbose {
+                println!("Nothing saved in {}", path);
+            }
+            T::default()
+        }
+    }
+}
+
+//Devuelve false si no se pudo guardar
+pub fn guardar<T: Serialize>(path: &str, guardado: &T, verbose: bool) -> bool {
+    let contenido = toml::to_string(guardado).unwrap();
+
+    //Se escribe aparte y se renombra para no dejar el archivo a medias
+    let temporal = String::from(path) + ".tmp";
+    if let Some(dir) = Path::new(path).parent() {
+        let _ = fs::create_dir_all(dir);
+    }
+    if let Err(e) = fs::write(&temporal, contenido).and_then(|_| fs::rename(&temporal, path)) {
+        if verbose {
+            println!("Failed to save {}: {}", path, e);
+        }
+        return false;
+    }
+    true
+}
@@ -0,0 +1,417 @@
+use rppal::gpio::{Gpio, IoPin, Mode};
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::{Arc, Mutex};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use crate::config::{self, Config};
+use crate::firmware::{self, firmware_upload};
+use crate::relay::relay_por_nombre;
+use crate::server::Canales;
+
+/* Comandos ICSP de 6 bits de los PIC16F1 */
+const CARGAR_CONFIGURACION: u8 = 0x00;
+const CARGAR_DATOS: u8 = 0x02;
+const LEER_DATOS: u8 = 0x04;
+const INCREMENTAR_DIRECCION: u8 = 0x06;
+const PROGRAMAR: u8 = 0x08;
+const BORRADO_TOTAL: u8 = 0x09;
+const RESET_DIRECCION: u8 = 0x16;
+
+//Clave de entrada en bajo voltaje, "MCHP"
+const CLAVE_LVP: u32 = 0x4D434850;
+
+//Direccion de palabra -> palabra de 14 bits
+type Imagen = BTreeMap<u32, u16>;
+
+pub type ProgramadorPic = Arc<Mutex<Programador>>;
+
+#[derive(Default, Clone, Copy, PartialEq)]
+enum Etapa {
+    #[default]
+    Inactivo = 0,
+    Borrando = 1,
+    Escribiendo = 2,
+    Verificando = 3,
+    Terminado = 4,
+    Error = 0xF0,
+}
+
+/* ESTADO DE LA PROGRAMACION, CONSULTADO MIENTRAS CORRE */
+#[derive(Default)]
+pub struct Programador {
+    etapa: Etapa,
+    hechas: u32,
+    total: u32,
+    mensaje: String,
+}
+
+impl Programador {
+    fn ocupado(&self) -> bool {
+        matches!(
+            self.etapa,
+            Etapa::Borrando | Etapa::Escribiendo | Etapa::Verificando
+        )
+    }
+}
+
+fn avanzar(programador: &ProgramadorPic, etapa: Etapa, hechas: u32, total: u32) {
+    let mut programador = programador.lock().unwrap();
+    programador.etapa = etapa;
+    programador.hechas = hechas;
+    programador.total = total;
+}
+
+/* ICSP POR GPIO */
+//Al terminar, bien o mal, PGC y PGD quedan como entradas
+struct Icsp {
+    pgc: IoPin,
+    pgd: IoPin,
+}
+
+impl Drop for Icsp {
+    fn drop(&mut self) {
+        self.pgc.set_mode(Mode::Input);
+        self.pgd.set_mode(Mode::Input);
+    }
+}
+
+impl Icsp {
+    fn abrir(config: &config::Programmer) -> Result<Self, String> {
+        let (pgc, pgd) = match (config.pgc_pin, config.pgd_pin) {
+            (Some(pgc), Some(pgd)) => (pgc, pgd),
+            _ => return Err(String::from("pgc_pin and pgd_pin not configured")),
+        };
+        let gpio = Gpio::new().map_err(|e| e.to_string())?;
+        let mut pgc = gpio
+            .get(pgc)
+            .map_err(|e| e.to_string())?
+            .into_io(Mode::Output);
+        let mut pgd = gpio
+            .get(pgd)
+            .map_err(|e| e.to_string())?
+            .into_io(Mode::Output);
+        pgc.set_reset_on_drop(false);
+        pgd.set_reset_on_drop(false);
+        pgc.set_low();
+        pgd.set_low();
+        Ok(Icsp { pgc, pgd })
+    }
+
+    //Un sleep de 1 µs duerme decenas de µs en Linux, se espera dando vueltas
+    fn esperar() {
+        let inicio = Instant::now();
+        while inicio.elapsed() < Duration::from_micros(1) {
+            std::hint::spin_loop();
+        }
+    }
+
+    //El PIC toma el dato en el flanco de bajada, bit menos significativo primero
+    fn enviar(&mut self, valor: u32, bits: u8) {
+        for n in 0..bits {
+            if (valor >> n) & 1 != 0 {
+                self.pgd.set_high();
+            } else {
+                self.pgd.set_low();
+            }
+            self.pgc.set_high();
+            Icsp::esperar();
+            self.pgc.set_low();
+            Icsp::esperar();
+        }
+    }
+
+    //Con MCLR en bajo: la clave y un pulso de reloj mas
+    fn entrar(&mut self) {
+        sleep(Duration::from_millis(1));
+        self.enviar(CLAVE_LVP, 32);
+        self.enviar(0, 1);
+        sleep(Duration::from_millis(1));
+    }
+
+    fn comando(&mut self, comando: u8) {
+        self.enviar(comando as u32, 6);
+        Icsp::esperar();
+    }
+
+    //Dato de 16 bits: inicio, 14 bits de dato y fin
+    fn cargar(&mut self, comando: u8, dato: u16) {
+        self.comando(comando);
+        self.enviar(((dato & 0x3FFF) as u32) << 1, 16);
+        Icsp::esperar();
+    }
+
+    fn leer(&mut self, comando: u8) -> u16 {
+        self.comando(comando);
+        self.pgd.set_mode(Mode::Input);
+        let mut valor = 0;
+        for n in 0..16 {
+            self.pgc.set_high();
+            Icsp::esperar();
+            if self.pgd.is_high() {
+                valor |= 1 << n;
+            }
+            self.pgc.set_low();
+            Icsp::esperar();
+        }
+        self.pgd.set_mode(Mode::Output);
+        (valor >> 1) & 0x3FFF
+    }
+
+    //El contador de programa solo avanza
+    fn ir_a(&mut self, pc: &mut u32, direccion: u32) {
+        while *pc < direccion {
+            self.comando(INCREMENTAR_DIRECCION);
+            *pc += 1;
+        }
+    }
+
+    fn verificar(&mut self, pc: &mut u32, direccion: u32, palabra: u16) -> Result<(), String> {
+        self.ir_a(pc, direccion);
+        let leida = self.leer(LEER_DATOS);
+        if leida != palabra {
+            return Err(format!(
+                "verify failed at 0x{:04X}: wrote {:04X}, read {:04X}",
+                direccion, palabra, leida
+            ));
+        }
+        Ok(())
+    }
+}
+
+//Borra, escribe por filas la memoria de programa y de a una las de configuracion, y verifica
+fn programar_icsp(
+    imagen: &Imagen,
+    config: &config::Programmer,
+    programador: &ProgramadorPic,
+) -> Result<(), String> {
+    let mut icsp = Icsp::abrir(config)?;
+    let total = imagen.len() as u32;
+    icsp.entrar();
+
+    avanzar(programador, Etapa::Borrando, 0, total);
+    icsp.cargar(CARGAR_CONFIGURACION, 0x3FFF);
+    icsp.comando(BORRADO_TOTAL);
+    sleep(Duration::from_millis(6));
+
+    avanzar(programador, Etapa::Escribiendo, 0, total);
+    let mut hechas = 0;
+    let mut pc = 0;
+    icsp.comando(RESET_DIRECCION);
+    let filas: BTreeSet<u32> = imagen
+        .range(..0x8000)
+        .map(|(direccion, _)| direccion - direccion % config.row_words)
+        .collect();
+    for fila in filas {
+        let fin = fila + config.row_words;
+        let palabras = imagen.range(fila..fin).count() as u32;
+        for direccion in fila..fin {
+            icsp.ir_a(&mut pc, direccion);
+            icsp.cargar(CARGAR_DATOS, *imagen.get(&direccion).unwrap_or(&0x3FFF));
+        }
+        icsp.comando(PROGRAMAR);
+        sleep(Duration::from_millis(3));
+        hechas += palabras;
+        avanzar(programador, Etapa::Escribiendo, hechas, total);
+    }
+
+    icsp.cargar(CARGAR_CONFIGURACION, 0x3FFF);
+    pc = 0x8000;
+    for (direccion, palabra) in imagen.range(0x8000..) {
+        icsp.ir_a(&mut pc, *direccion);
+        icsp.cargar(CARGAR_DATOS, *palabra);
+        icsp.comando(PROGRAMAR);
+        sleep(Duration::from_millis(6));
+        hechas += 1;
+        avanzar(programador, Etapa::Escribiendo, hechas, total);
+    }
+
+    avanzar(programador, Etapa::Verificando, 0, total);
+    hechas = 0;
+    icsp.comando(RESET_DIRECCION);
+    pc = 0;
+    for (direccion, palabra) in imagen.range(..0x8000) {
+        icsp.verificar(&mut pc, *direccion, *palabra)?;
+        hechas += 1;
+        if hechas % config.row_words == 0 {
+            avanzar(programador, Etapa::Verificando, hechas, total);
+        }
+    }
+    icsp.cargar(CARGAR_CONFIGURACION, 0x3FFF);
+    pc = 0x8000;
+    for (direccion, palabra) in imagen.range(0x8000..) {
+        icsp.verificar(&mut pc, *direccion, *palabra)?;
+        hechas += 1;
+    }
+    avanzar(programador, Etapa::Verificando, hechas, total);
+    Ok(())
+}
+
+//Dos bytes por palabra en little endian, los bytes que faltan quedan borrados.
+//Solo memoria de programa, ids de usuario y palabras de configuracion
+fn palabras(imagen: &firmware::Imagen, config: &config::Programmer) -> Result<Imagen, String> {
+    let mut palabras = Imagen::new();
+    for (direccion, byte) in imagen {
+        let palabra = palabras.entry(direccion / 2).or_insert(0x3FFF);
+        if direccion % 2 == 0 {
+            *palabra = (*palabra & 0xFF00) | *byte as u16;
+        } else {
+            *palabra = (*palabra & 0x00FF) | (*byte as u16) << 8;
+        }
+    }
+
+    let configuracion = config.config_address..config.config_address + config.config_words;
+    for (direccion, palabra) in palabras.iter_mut() {
+        let id_de_usuario = (0x8000..0x8004).contains(direccion);
+        if *direccion >= config.flash_words && !id_de_usuario && !configuracion.contains(direccion)
+        {
+            return Err(format!("address 0x{:04X} out of the PIC memory", direccion));
+        }
+        *palabra &= 0x3FFF;
+    }
+    Ok(palabras)
+}
+
+async fn relay_icsp(
+    nombre: &str,
+    encendido: bool,
+    canales: &Canales,
+    config: &Config,
+) -> Result<(), String> {
+    let respuesta =
+        relay_por_nombre(encendido as u32, nombre, &config.relay, &canales.relay_tx).await;
+    if respuesta == [0xF0, 0xF0] {
+        return Err(format!("relay {} not defined", nombre));
+    }
+    Ok(())
+}
+
+//Con el PIC en reset y las lineas de programacion conectadas por los relays
+async fn programar(imagen: Imagen, canales: Canales, config: Arc<Config>, verbose: bool) {
+    let programador = canales.programador.clone();
+    let exclusion = canales.exclusion.clone();
+    let _escritura = exclusion.write().await;
+
+    let mut resultado = relay_icsp("reset", true, &canales, &config).await;
+    if resultado.is_ok() {
+        resultado = relay_icsp("program", true, &canales, &config).await;
+    }
+    if resultado.is_ok() {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        let progreso = programador.clone();
+        let config = config.clone();
+        resultado = tokio::task::spawn_blocking(move || {
+            programar_icsp(&imagen, &config.programmer, &progreso)
+        })
+        .await
+        .unwrap();
+    }
+    let _ = relay_icsp("program", false, &canales, &config).await;
+    let _ = relay_icsp("reset", false, &canales, &config).await;
+
+    let mut programador = programador.lock().unwrap();
+    match resultado {
+        Ok(()) => {
+            programador.etapa = Etapa::Terminado;
+            programador.mensaje = format!("{} words programmed and verified", programador.total);
+        }
+        Err(e) => {
+            programador.etapa = Etapa::Error;
+            programador.mensaje = e;
+        }
+    }
+    if verbose {
+        println!("Pic programming: {}", programador.mensaje);
+    }
+}
+
+//msg: [opcode, comando << 4, largo], comando 0: empezar a recibir el HEX,
+//1: seguir recibiendolo, 2: validar y programar lo recibido,
+//4: programar la ultima imagen de firmware valida
+pub fn pic_program(
+    msg: u32,
+    carga: Vec<u8>,
+    canales: &Canales,
+    config: &Arc<Config>,
+    verbose: bool,
+) -> [u8; 2] {
+    let mut programador = canales.programador.lock().unwrap();
+    if programador.ocupado() {
+        if verbose {
+            println!("Pic programming in progress");
+        }
+        return [0xF0, 0xF0];
+    }
+
+    let mut firmware = canales.firmware.lock().unwrap();
+    let resultado = match (msg >> 20) & 0x0F {
+        //Como un HEX subido con los comandos de firmware
+        comando @ (0 | 1) => {
+            drop(firmware);
+            return firmware_upload(
+                comando << 20,
+                carga,
+                &canales.firmware,
+                &config.firmware,
+                verbose,
+            );
+        }
+        2 => firmware.comprobar(&config.firmware),
+        4 => Ok(()),
+        _ => {
+            if verbose {
+                println!("Invalid programming command");
+            }
+            return [0xF0, 0xF0];
+        }
+    };
+    let imagen = resultado
+        .and_then(|_| {
+            firmware
+                .imagen()
+                .ok_or(String::from("no valid firmware image"))
+        })
+        .and_then(|imagen| palabras(imagen, &config.programmer));
+
+    let imagen = match imagen {
+        Ok(imagen) => imagen,
+        Err(e) => {
+            if verbose {
+                println!("Invalid firmware image: {}", e);
+            }
+            *programador = Programador {
+                etapa: Etapa::Error,
+                mensaje: e,
+                ..Default::default()
+            };
+            return [0xF0, 0xF0];
+        }
+    };
+    if verbose {
+        println!("Programming {} words into the pic", imagen.len());
+    }
+    *programador = Programador {
+        etapa: Etapa::Borrando,
+        total: imagen.len() as u32,
+        ..Default::default()
+    };
+    tokio::spawn(programar(
+        imagen,
+        canales.resubscribe(),
+        config.clone(),
+        verbose,
+    ));
+    [0, 0]
+}
+
+//Respuesta: [etapa, palabras hechas (u32), palabras totales (u32), bytes recibidos (u32), mensaje]
+pub fn pic_program_status(canales: &Canales) -> Vec<u8> {
+    let recibidos = canales.firmware.lock().unwrap().recibidos() as u32;
+    let programador = canales.programador.lock().unwrap();
+    let mut respuesta = vec![programador.etapa as u8];
+    respuesta.extend_from_slice(&programador.hechas.to_be_bytes());
+    respuesta.extend_from_slice(&programador.total.to_be_bytes());
+    respuesta.extend_from_slice(&recibidos.to_be_bytes());
+    respuesta.extend_from_slice(programador.mensaje.as_bytes());
+    respuesta
+}
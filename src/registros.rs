@@ -0,0 +1,526 @@
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::fs;
+use std::io::ErrorKind;
+use std::sync::Arc;
+
+use crate::config::RegisterDump;
+use crate::persistencia::guardar;
+use crate::spi::{escribir_pic, leer_pic, PeticionSpi};
+
+/* MAPA DE REGISTROS DEL PIC DESDE ARCHIVO TOML */
+pub type MapaRegistros = Arc<Vec<Registro>>;
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct Mapa {
+    register: Vec<Registro>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Registro {
+    pub name: String,
+    pub address: u8,
+    //Bits de dato, el bit 15 es la paridad
+    #[serde(default = "ancho_por_defecto")]
+    pub width: u8,
+    #[serde(default)]
+    pub access: Acceso,
+    pub reset: Option<u16>,
+    #[serde(default)]
+    pub field: Vec<Campo>,
+}
+
+fn ancho_por_defecto() -> u8 {
+    15
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Campo {
+    pub name: String,
+    pub lsb: u8,
+    #[serde(default = "ancho_de_bit")]
+    pub width: u8,
+}
+
+fn ancho_de_bit() -> u8 {
+    1
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Acceso {
+    #[default]
+    ReadWrite,
+    ReadOnly,
+    WriteOnly,
+}
+
+impl Registro {
+    pub fn mascara(&self) -> u16 {
+        mascara(self.width)
+    }
+
+    pub fn campo(&self, nombre: &str) -> Option<&Campo> {
+        self.field.iter().find(|c| c.name == nombre)
+    }
+
+    //Aclaracion para los valores distintos al de reset
+    fn reset_distinto(&self, valor: u16) -> String {
+        match self.reset {
+            Some(reset) if reset != valor => format!(", reset 0x{:04X}", reset),
+            _ => String::new(),
+        }
+    }
+
+    //Valor del registro y de cada campo, una linea por cada uno
+    pub fn decodificar(&self, valor: u16) -> String {
+        let mut texto = format!(
+            "{} = 0x{:04X}{}\n",
+            self.name,
+            valor,
+            self.reset_distinto(valor)
+        );
+        for campo in &self.field {
+            let _ = writeln!(texto, "  {} = {}", campo.name, campo.extraer(valor));
+        }
+        texto
+    }
+}
+
+impl Campo {
+    fn mascara(&self) -> u16 {
+        mascara(self.width) << self.lsb
+    }
+
+    pub fn extraer(&self, valor: u16) -> u16 {
+        (valor & self.mascara()) >> self.lsb
+    }
+
+    fn insertar(&self, registro: u16, valor: u16) -> u16 {
+        (registro & !self.mascara()) | (valor << self.lsb & self.mascara())
+    }
+}
+
+fn mascara(ancho: u8) -> u16 {
+    ((1u32 << ancho) - 1) as u16
+}
+
+//Sin el archivo el mapa queda vacio
+//Los registros a volcar tienen que estar en el mapa
+pub fn cargar(path: &str, volcado: &RegisterDump) -> Result<Vec<Registro>, String> {
+    let contenido = match fs::read_to_string(path) {
+        Ok(c) => c,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(format!("Failed to read {}: {}", path, e)),
+    };
+    let mapa: Mapa =
+        toml::from_str(&contenido).map_err(|e| format!("Invalid register map {}: {}", path, e))?;
+    validar(&mapa.register).map_err(|e| format!("Invalid register map {}: {}", path, e))?;
+    for nombre in &volcado.registers {
+        if !mapa.register.iter().any(|r| &r.name == nombre) {
+            return Err(format!(
+                "Invalid config: register {} to dump not in the register map",
+                nombre
+            ));
+        }
+    }
+    Ok(mapa.register)
+}
+
+fn validar(registros: &[Registro]) -> Result<(), String> {
+    for (n, registro) in registros.iter().enumerate() {
+        if registros[..n].iter().any(|r| r.name == registro.name) {
+            return Err(format!("register {} defined twice", registro.name));
+        }
+        if registros[..n].iter().any(|r| r.address == registro.address) {
+            return Err(format!("address {} used twice", registro.address));
+        }
+        if registro.width == 0 || registro.width > 15 {
+            return Err(format!("register {} width out of range", registro.name));
+        }
+        if registro.reset.is_some_and(|r| r & !registro.mascara() != 0) {
+            return Err(format!("register {} reset value too wide", registro.name));
+        }
+        let mut ocupados = 0;
+        for campo in &registro.field {
+            if campo.width == 0 || campo.lsb as u32 + campo.width as u32 > registro.width as u32 {
+                return Err(format!(
+                    "field {}.{} out of the register",
+                    registro.name, campo.name
+                ));
+            }
+            if ocupados & campo.mascara() != 0 {
+                return Err(format!(
+                    "field {}.{} overlaps another field",
+                    registro.name, campo.name
+                ));
+            }
+            ocupados |= campo.mascara();
+        }
+    }
+    Ok(())
+}
+
+//Decimal, 0x hexadecimal o 0b binario
+pub fn parsear_valor(texto: &str) -> Option<u16> {
+    let texto = texto.trim();
+    if let Some(hexa) = texto.strip_prefix("0x").or(texto.strip_prefix("0X")) {
+        u16::from_str_radix(hexa, 16).ok()
+    } else if let Some(binario) = texto.strip_prefix("0b").or(texto.strip_prefix("0B")) {
+        u16::from_str_radix(binario, 2).ok()
+    } else {
+        texto.parse().ok()
+    }
+}
+
+pub async fn leer_registro(
+    registro: &Registro,
+    rx: &mut tokio::sync::broadcast::Receiver<Vec<u8>>,
+    tx: &tokio::sync::mpsc::Sender<PeticionSpi>,
+) -> Result<u16, String> {
+    if registro.access == Acceso::WriteOnly {
+        return Err(format!("{} is write only", registro.name));
+    }
+    let palabra = leer_pic(0x3C000000 | (registro.address as u32) << 16, rx, tx)
+        .await
+        .map_err(|palabra| format!("parity error reading {}: {:04X}", registro.name, palabra))?;
+    Ok(palabra & registro.mascara())
+}
+
+async fn escribir_registro(
+    registro: &Registro,
+    valor: u16,
+    rx: &mut tokio::sync::broadcast::Receiver<Vec<u8>>,
+    tx: &tokio::sync::mpsc::Sender<PeticionSpi>,
+) -> Result<(), String> {
+    if registro.access == Acceso::ReadOnly {
+        return Err(format!("{} is read only", registro.name));
+    }
+    let msg = 0x25000000 | (registro.address as u32) << 16 | valor as u32;
+    escribir_pic(msg, rx, tx).await.map_err(|respuesta| {
+        format!("parity error writing {}: {:04X}", registro.name, respuesta)
+    })?;
+    Ok(())
+}
+
+//Texto: REGISTRO, REGISTRO.CAMPO, REGISTRO=VALOR o REGISTRO.CAMPO=VALOR
+//Devuelve el registro decodificado, despues de escribirlo si se pidio
+async fn ejecutar(
+    texto: &str,
+    registros: &[Registro],
+    rx: &mut tokio::sync::broadcast::Receiver<Vec<u8>>,
+    tx: &tokio::sync::mpsc::Sender<PeticionSpi>,
+) -> Result<String, String> {
+    let (nombre, valor) = match texto.split_once('=') {
+        Some((nombre, valor)) => (
+            nombre.trim(),
+            Some(parsear_valor(valor).ok_or(format!("invalid value {}", valor.trim()))?),
+        ),
+        None => (texto.trim(), None),
+    };
+    let (nombre, campo) = match nombre.split_once('.') {
+        Some((registro, campo)) => (registro, Some(campo)),
+        None => (nombre, None),
+    };
+    let registro = registros
+        .iter()
+        .find(|r| r.name == nombre)
+        .ok_or(format!("unknown register {}", nombre))?;
+    let campo = match campo {
+        Some(c) => Some(
+            registro
+                .campo(c)
+                .ok_or(format!("unknown field {}.{}", nombre, c))?,
+        ),
+        None => None,
+    };
+
+    if let Some(valor) = valor {
+        let nuevo = match campo {
+            Some(campo) => {
+                if valor & !mascara(campo.width) != 0 {
+                    return Err(format!("value too wide for {}.{}", nombre, campo.name));
+                }
+                //Los demas campos se conservan, hace falta leer el registro
+                let actual = leer_registro(registro, rx, tx).await?;
+                campo.insertar(actual, valor)
+            }
+            None => {
+                if valor & !registro.mascara() != 0 {
+                    return Err(format!("value too wide for {}", nombre));
+                }
+                valor
+            }
+        };
+        escribir_registro(registro, nuevo, rx, tx).await?;
+        if registro.access == Acceso::WriteOnly {
+            return Ok(registro.decodificar(nuevo));
+        }
+    }
+
+    let valor = leer_registro(registro, rx, tx).await?;
+    Ok(match campo {
+        Some(campo) => format!("{}.{} = {}\n", nombre, campo.name, campo.extraer(valor)),
+        None => registro.decodificar(valor),
+    })
+}
+
+//Respuesta: [estado, texto], estado 0xF0 y el motivo si fallo
+pub async fn pic_register(
+    carga: Vec<u8>,
+    registros: &[Registro],
+    rx: &mut tokio::sync::broadcast::Receiver<Vec<u8>>,
+    tx: &tokio::sync::mpsc::Sender<PeticionSpi>,
+    verbose: bool,
+) -> Vec<u8> {
+    let texto = String::from_utf8_lossy(&carga);
+    let (estado, texto) = match ejecutar(&texto, registros, rx, tx).await {
+        Ok(texto) => (0, texto),
+        Err(e) => {
+            if verbose {
+                println!("Register command failed: {}", e);
+            }
+            (0xF0, e)
+        }
+    };
+    if verbose {
+        print!("{}", texto);
+    }
+    let mut respuesta = vec![estado];
+    respuesta.extend_from_slice(texto.as_bytes());
+    respuesta
+}
+
+/* VOLCADO Y COMPARACION CON LA REFERENCIA */
+type Captura = BTreeMap<String, u16>;
+
+async fn capturar(
+    config: &RegisterDump,
+    registros: &[Registro],
+    rx: &mut tokio::sync::broadcast::Receiver<Vec<u8>>,
+    tx: &tokio::sync::mpsc::Sender<PeticionSpi>,
+) -> Result<Captura, String> {
+    let elegidos: Vec<&Registro> = if config.registers.is_empty() {
+        registros
+            .iter()
+            .filter(|r| r.access != Acceso::WriteOnly)
+            .collect()
+    } else {
+        config
+            .registers
+            .iter()
+            .filter_map(|nombre| registros.iter().find(|r| &r.name == nombre))
+            .collect()
+    };
+    if elegidos.is_empty() {
+        return Err(String::from("no registers to dump"));
+    }
+
+    let mut captura = Captura::new();
+    for registro in elegidos {
+        let valor = leer_registro(registro, rx, tx).await?;
+        captura.insert(registro.name.clone(), valor);
+    }
+    Ok(captura)
+}
+
+fn cargar_referencia(path: &str) -> Result<Captura, String> {
+    let contenido =
+        fs::read_to_string(path).map_err(|e| format!("Failed to read {}: {}", path, e))?;
+    toml::from_str(&contenido).map_err(|e| format!("Invalid golden snapshot {}: {}", path, e))
+}
+
+//Registros distintos a la referencia, con los campos que cambiaron
+fn comparar(captura: &Captura, referencia: &Captura, registros: &[Registro]) -> String {
+    let mut texto = String::new();
+    for (nombre, valor) in captura {
+        let registro = registros.iter().find(|r| &r.name == nombre);
+        let reset = registro.map_or(String::new(), |r| r.reset_distinto(*valor));
+        let esperado = match referencia.get(nombre) {
+            Some(esperado) => *esperado,
+            None => {
+                let _ = writeln!(
+                    texto,
+                    "{} = 0x{:04X}, not in golden{}",
+                    nombre, valor, reset
+                );
+                continue;
+            }
+        };
+        if esperado == *valor {
+            continue;
+        }
+        let _ = writeln!(
+            texto,
+            "{} = 0x{:04X}, golden 0x{:04X}{}",
+            nombre, valor, esperado, reset
+        );
+        if let Some(registro) = registro {
+            for campo in &registro.field {
+                if campo.extraer(*valor) != campo.extraer(esperado) {
+                    let _ = writeln!(
+                        texto,
+                        "  {} = {}, golden {}",
+                        campo.name,
+                        campo.extraer(*valor),
+                        campo.extraer(esperado)
+                    );
+                }
+            }
+        }
+    }
+    texto
+}
+
+//comando 0: leer y guardar la captura, 1: ademas compararla con la referencia,
+//2: guardar la lectura como referencia
+//Respuesta: [estado, texto], estado 0x01 si hay diferencias y 0xF0 si fallo
+pub async fn pic_dump(
+    msg: u32,
+    config: &RegisterDump,
+    registros: &[Registro],
+    rx: &mut tokio::sync::broadcast::Receiver<Vec<u8>>,
+    tx: &tokio::sync::mpsc::Sender<PeticionSpi>,
+    verbose: bool,
+) -> Vec<u8> {
+    let comando = (msg >> 20) & 0x0F;
+    let resultado = match comando {
+        0..=2 => capturar(config, registros, rx, tx).await,
+        _ => Err(String::from("invalid dump command")),
+    };
+    let resultado = resultado.and_then(|captura| match comando {
+        0 => {
+            guardar(&config.snapshot_file, &captura, verbose);
+            Ok((
+                0,
+                captura
+                    .iter()
+                    .fold(String::new(), |mut texto, (nombre, valor)| {
+                        let reset = registros
+                            .iter()
+                            .find(|r| &r.name == nombre)
+                            .map_or(String::new(), |r| r.reset_distinto(*valor));
+                        let _ = writeln!(texto, "{} = 0x{:04X}{}", nombre, valor, reset);
+                        texto
+                    }),
+            ))
+        }
+        1 => {
+            guardar(&config.snapshot_file, &captura, verbose);
+            let referencia = cargar_referencia(&config.golden_file)?;
+            let diferencias = comparar(&captura, &referencia, registros);
+            Ok(((!diferencias.is_empty()) as u8, diferencias))
+        }
+        _ => {
+            if !guardar(&config.golden_file, &captura, verbose) {
+                return Err(format!("Failed to save {}", config.golden_file));
+            }
+            Ok((0, format!("{} registers saved as golden\n", captura.len())))
+        }
+    });
+
+    let (estado, texto) = resultado.unwrap_or_else(|e| (0xF0, e + "\n"));
+    if verbose {
+        print!("{}", texto);
+    }
+    let mut respuesta = vec![estado];
+    respuesta.extend_from_slice(texto.as_bytes());
+    respuesta
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn campo(name: &str, lsb: u8, width: u8) -> Campo {
+        Campo {
+            name: String::from(name),
+            lsb,
+            width,
+        }
+    }
+
+    fn registro(width: u8, reset: Option<u16>, field: Vec<Campo>) -> Registro {
+        Registro {
+            name: String::from("CONTROL"),
+            address: 0x10,
+            width,
+            access: Acceso::ReadWrite,
+            reset,
+            field,
+        }
+    }
+
+    #[test]
+    fn extraer_campos() {
+        let modo = campo("MODE", 1, 3);
+        assert_eq!(modo.extraer(0b1011), 0b101);
+        assert_eq!(modo.extraer(0xFFFF), 0b111);
+        assert_eq!(modo.extraer(0b0001), 0);
+        assert_eq!(campo("TOP", 14, 1).extraer(0x4000), 1);
+    }
+
+    #[test]
+    fn insertar_campos() {
+        let modo = campo("MODE", 1, 3);
+        assert_eq!(modo.insertar(0b10001, 0b010), 0b10101);
+        assert_eq!(modo.insertar(0xFFFF, 0), 0xFFF1);
+        //Lo que no entra en el campo no toca a los demas bits
+        assert_eq!(modo.insertar(0, 0xFF), 0b1110);
+        assert_eq!(modo.extraer(modo.insertar(0x1234, 5)), 5);
+    }
+
+    #[test]
+    fn decodificar_con_reset() {
+        let control = registro(8, Some(0x01), vec![campo("ENABLE", 0, 1)]);
+        assert_eq!(
+            control.decodificar(0x01),
+            "CONTROL = 0x0001\n  ENABLE = 1\n"
+        );
+        assert_eq!(
+            control.decodificar(0x00),
+            "CONTROL = 0x0000, reset 0x0001\n  ENABLE = 0\n"
+        );
+        assert_eq!(
+            registro(8, None, Vec::new()).decodificar(0x00),
+            "CONTROL = 0x0000\n"
+        );
+    }
+
+    #[test]
+    fn valores() {
+        assert_eq!(parsear_valor("12"), Some(12));
+        assert_eq!(parsear_valor(" 0x1F "), Some(0x1F));
+        assert_eq!(parsear_valor("0b101"), Some(5));
+        assert_eq!(parsear_valor("0x10000"), None);
+        assert_eq!(parsear_valor("doce"), None);
+    }
+
+    #[test]
+    fn mapa_invalido() {
+        let ancho = registro(8, Some(0x100), Vec::new());
+        assert_eq!(
+            validar(&[ancho]).unwrap_err(),
+            "register CONTROL reset value too wide"
+        );
+        let afuera = registro(8, None, vec![campo("MODE", 6, 3)]);
+        assert_eq!(
+            validar(&[afuera]).unwrap_err(),
+            "field CONTROL.MODE out of the register"
+        );
+        let pisados = registro(8, None, vec![campo("A", 0, 2), campo("B", 1, 1)]);
+        assert_eq!(
+            validar(&[pisados]).unwrap_err(),
+            "field CONTROL.B overlaps another field"
+        );
+        assert_eq!(
+            validar(&[registro(16, None, Vec::new())]).unwrap_err(),
+            "register CONTROL width out of range"
+        );
+        assert!(validar(&[registro(15, Some(0x7FFF), vec![campo("A", 0, 15)])]).is_ok());
+    }
+}
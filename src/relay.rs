@@ -1,48 +1,452 @@
 use rppal::gpio::{Gpio, OutputPin};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+use tokio::time::{sleep_until, Duration, Instant};
 
+use crate::config;
+use crate::persistencia::{cargar, guardar};
+use crate::seguro::Listo;
+
+struct Relay {
+    nombre: String,
+    pin: OutputPin,
+    active_low: bool,
+    estado_seguro: bool,
+    ciclos: Ciclos,
+    pulso: Option<Pulso>,
+}
+
+impl Relay {
+    //Lee el nivel real del pin
+    fn encendido(&self) -> bool {
+        self.pin.is_set_high() != self.active_low
+    }
+
+    fn cortar_pulso(&mut self) {
+        if let Some(pulso) = self.pulso.take() {
+            pulso.cortar();
+        }
+    }
+}
+
+/* CICLOS Y DESGASTE */
+struct Ciclos {
+    cuenta: u32,
+    fin_de_vida: Option<u32>,
+}
+
+impl Ciclos {
+    fn gastado(&self) -> bool {
+        matches!(self.fin_de_vida, Some(limite) if self.cuenta >= limite)
+    }
+
+    //Cuenta un encendido, true justo al llegar al fin de vida
+    fn contar(&mut self) -> bool {
+        self.cuenta = self.cuenta.saturating_add(1);
+        self.fin_de_vida == Some(self.cuenta)
+    }
+
+    //ciclos (u32), fin de vida (u32, 0 sin limite), estado (bit 0 encendido, bit 1 gastado)
+    fn desgaste(&self, encendido: bool) -> [u8; 9] {
+        let mut respuesta = [0; 9];
+        respuesta[..4].copy_from_slice(&self.cuenta.to_be_bytes());
+        respuesta[4..8].copy_from_slice(&self.fin_de_vida.unwrap_or(0).to_be_bytes());
+        respuesta[8] = encendido as u8 | (self.gastado() as u8) << 1;
+        respuesta
+    }
+}
+
+/* PULSO EN CURSO */
+//Cuando se suelta, quien lo pidio y lo que se le responde al soltarlo
+struct Pulso {
+    fin: Instant,
+    respuesta: oneshot::Sender<Vec<u8>>,
+    eco: [u8; 2],
+}
+
+impl Pulso {
+    fn vencido(&self, ahora: Instant) -> bool {
+        self.fin <= ahora
+    }
+
+    fn soltar(self) {
+        let _ = self.respuesta.send(self.eco.to_vec());
+    }
+
+    //Un pulso que termina antes de tiempo responde 0xF0F0
+    fn cortar(self) {
+        let _ = self.respuesta.send(vec![0xF0, 0xF0]);
+    }
+}
+
+//Cada pedido lleva por donde responderle, asi la respuesta solo le llega a quien pidio
+pub type PeticionRelay = ([u8; 4], oneshot::Sender<Vec<u8>>);
+
+/* Estado guardado en disco entre reinicios */
+#[derive(Serialize, Deserialize)]
+struct Guardado {
+    on: bool,
+}
+
+/* BANCO DE RELAYS */
+//msg: [opcode, comando << 4 | indice, valor]
+//comando 0: nivel, 1: pulso de valor ms, 2: estado de todos como mascara,
+//3: estado del relay, 4: ciclos y desgaste de todos
 pub async fn relay_handler(
     verbose: bool,
-    mut rx: tokio::sync::mpsc::Receiver<[u8; 4]>,
-    tx: tokio::sync::broadcast::Sender<[u8; 2]>,
-    pin: u8,
+    mut rx: tokio::sync::mpsc::Receiver<PeticionRelay>,
+    configuracion: Vec<config::Relay>,
+    banco: config::RelayBank,
+    mut seguro: tokio::sync::broadcast::Receiver<Listo>,
 ) {
     let gpio = Gpio::new().unwrap();
-    let mut relay_pin = gpio.get(pin).unwrap().into_output(); //reset 12, program 0
-    relay_pin.set_low();
+    //Los ciclos se guardan siempre, el estado solo con persist
+    let guardado: BTreeMap<String, Guardado> = if banco.persist {
+        cargar(&banco.state_file, verbose)
+    } else {
+        BTreeMap::new()
+    };
+    let ciclos: BTreeMap<String, u32> = cargar(&banco.cycles_file, verbose);
+    let mut escritura = None;
+    let mut relays: Vec<Relay> = configuracion
+        .into_iter()
+        .map(|c| {
+            let pin = gpio
+                .get(c.pin)
+                .unwrap_or_else(|_| panic!("Falló gettear el gpio {}", c.pin))
+                .into_output();
+            let restaurado = guardado.get(&c.name).map(|g| g.on);
+            let ciclos = Ciclos {
+                cuenta: ciclos.get(&c.name).copied().unwrap_or(0),
+                fin_de_vida: c.end_of_life,
+            };
+            let mut relay = Relay {
+                nombre: c.name,
+                pin,
+                active_low: c.active_low,
+                estado_seguro: c.safe_state,
+                ciclos,
+                pulso: None,
+            };
+            if relay.ciclos.gastado() {
+                println!(
+                    "Relay {} reached its end of life, {} cycles",
+                    relay.nombre, relay.ciclos.cuenta
+                );
+            }
+            //Restaurar el estado guardado no cuenta como un ciclo
+            match (restaurado, c.initial) {
+                (Some(on), _) => poner_nivel(on, &mut relay),
+                (None, Some(on)) => relay_state(on as u16, &mut relay, verbose),
+                (None, None) => relay.pin.set_low(),
+            }
+            relay
+        })
+        .collect();
 
     loop {
-        let msg = rx.recv().await.unwrap();
+        //Los pulsos se sueltan aca, sin frenar los demas comandos
+        let proximo_pulso = relays
+            .iter()
+            .filter_map(|r| r.pulso.as_ref().map(|p| p.fin))
+            .min();
+        let fin_de_pulso = sleep_until(proximo_pulso.unwrap_or_else(Instant::now));
+        let (msg, tx) = tokio::select! {
+            msg = rx.recv() => msg.unwrap(),
+            Ok(_listo) = seguro.recv() => {
+                for relay in relays.iter_mut() {
+                    let estado = relay.estado_seguro as u16;
+                    relay.cortar_pulso();
+                    relay_state(estado, relay, verbose);
+                }
+                //Listo se suelta recien con el estado seguro escrito
+                guardar_relays(&relays, &banco, &mut escritura, verbose);
+                if let Some(escritura) = escritura.take() {
+                    let _ = escritura.await;
+                }
+                continue;
+            }
+            _ = fin_de_pulso, if proximo_pulso.is_some() => {
+                let ahora = Instant::now();
+                for relay in relays.iter_mut() {
+                    if let Some(pulso) = relay.pulso.take_if(|p| p.vencido(ahora)) {
+                        relay_state(0, relay, verbose);
+                        pulso.soltar();
+                    }
+                }
+                guardar_relays(&relays, &banco, &mut escritura, verbose);
+                continue;
+            }
+        };
         let mut arr = [0; 2];
         arr.clone_from_slice(&msg[2..]);
         let valor_nuevo = <u16>::from_be_bytes(arr);
+        let comando = msg[1] >> 4;
+
+        if comando == 2 {
+            let _ = tx.send(mascara(&relays).to_be_bytes().to_vec());
+            continue;
+        }
+
+        if comando == 4 {
+            let _ = tx.send(estado_de_desgaste(&relays));
+            continue;
+        }
 
-        relay_state(valor_nuevo, &mut relay_pin, verbose);
+        let relay = match relays.get_mut((msg[1] & 0x0F) as usize) {
+            Some(r) => r,
+            None => {
+                if verbose {
+                    println!("Relay out of range");
+                }
+                let _ = tx.send(vec![0xF0, 0xF0]);
+                continue;
+            }
+        };
 
-        let respuesta = arr;
+        let respuesta = match comando {
+            0 => {
+                relay.cortar_pulso();
+                relay_state(valor_nuevo, relay, verbose);
+                [0, relay.encendido() as u8]
+            }
+            1 => {
+                //Pulso: se activa por valor_nuevo ms y se responde al soltarlo en el loop
+                if verbose {
+                    println!("Relay {} pulse {} ms", relay.nombre, valor_nuevo);
+                }
+                relay.cortar_pulso();
+                relay_state(1, relay, verbose);
+                relay.pulso = Some(Pulso {
+                    fin: Instant::now() + Duration::from_millis(valor_nuevo as u64),
+                    respuesta: tx,
+                    eco: arr,
+                });
+                guardar_relays(&relays, &banco, &mut escritura, verbose);
+                continue;
+            }
+            3 => [0, relay.encendido() as u8],
+            _ => {
+                if verbose {
+                    println!("Invalid relay command");
+                }
+                let _ = tx.send(vec![0xF0, 0xF0]);
+                continue;
+            }
+        };
 
-        tx.send(respuesta).unwrap();
+        if comando != 3 {
+            guardar_relays(&relays, &banco, &mut escritura, verbose);
+        }
+
+        let _ = tx.send(respuesta.to_vec());
     }
 }
 
-pub async fn relay(
+async fn pedir(msg: u32, tx: &tokio::sync::mpsc::Sender<PeticionRelay>) -> Vec<u8> {
+    let (respuesta_tx, respuesta_rx) = oneshot::channel();
+    tx.send((msg.to_be_bytes(), respuesta_tx)).await.unwrap();
+    respuesta_rx.await.unwrap()
+}
+
+pub async fn relay(msg: u32, tx: &tokio::sync::mpsc::Sender<PeticionRelay>) -> [u8; 2] {
+    let respuesta = pedir(msg, tx).await;
+    [respuesta[0], respuesta[1]]
+}
+
+//Por relay: ciclos (u32), fin de vida (u32, 0 sin limite), estado (bit 0 encendido, bit 1 gastado)
+pub async fn relay_status(tx: &tokio::sync::mpsc::Sender<PeticionRelay>) -> Vec<u8> {
+    pedir(0x2E400000, tx).await
+}
+
+//Comandos viejos con un opcode por relay: [opcode, comando, valor]
+pub async fn relay_por_nombre(
     msg: u32,
-    rx: &mut tokio::sync::broadcast::Receiver<[u8; 2]>,
-    tx: &tokio::sync::mpsc::Sender<[u8; 4]>,
+    nombre: &str,
+    relays: &[config::Relay],
+    tx: &tokio::sync::mpsc::Sender<PeticionRelay>,
 ) -> [u8; 2] {
-    tx.send(msg.to_be_bytes()).await.unwrap();
-    rx.recv().await.unwrap()
+    match relays.iter().position(|r| r.name == nombre) {
+        Some(indice) => {
+            let comando = (msg >> 16) & 0x0F;
+            let msg = 0x2E000000 | (comando << 4 | indice as u32) << 16 | (msg & 0xFFFF);
+            relay(msg, tx).await
+        }
+        None => [0xF0, 0xF0],
+    }
+}
+
+fn mascara(relays: &[Relay]) -> u16 {
+    relays
+        .iter()
+        .enumerate()
+        .fold(0, |m, (n, r)| m | (r.encendido() as u16) << n)
 }
 
-fn relay_state(valor: u16, pin: &mut OutputPin, verbose: bool) {
-    if valor == 0 {
-        if verbose {
-            println!("Relay off");
+fn estado_de_desgaste(relays: &[Relay]) -> Vec<u8> {
+    let mut respuesta = Vec::with_capacity(relays.len() * 9);
+    for relay in relays {
+        respuesta.extend_from_slice(&relay.ciclos.desgaste(relay.encendido()));
+    }
+    respuesta
+}
+
+//Los archivos se escriben fuera del runtime, cada escritura despues de la anterior
+fn guardar_relays(
+    relays: &[Relay],
+    banco: &config::RelayBank,
+    escritura: &mut Option<JoinHandle<()>>,
+    verbose: bool,
+) {
+    let banco = banco.clone();
+    let guardado: BTreeMap<String, Guardado> = relays
+        .iter()
+        .map(|r| (r.nombre.clone(), Guardado { on: r.encendido() }))
+        .collect();
+    let ciclos: BTreeMap<String, u32> = relays
+        .iter()
+        .map(|r| (r.nombre.clone(), r.ciclos.cuenta))
+        .collect();
+    let anterior = escritura.take();
+    *escritura = Some(tokio::spawn(async move {
+        if let Some(anterior) = anterior {
+            let _ = anterior.await;
         }
-        pin.set_high();
-        return;
+        let _ = tokio::task::spawn_blocking(move || {
+            if banco.persist {
+                guardar(&banco.state_file, &guardado, verbose);
+            }
+            guardar(&banco.cycles_file, &ciclos, verbose);
+        })
+        .await;
+    }));
+}
+
+fn relay_state(valor: u16, relay: &mut Relay, verbose: bool) {
+    let encendido = valor != 0;
+    if encendido && !relay.encendido() && relay.ciclos.contar() {
+        println!(
+            "Relay {} reached its end of life, {} cycles",
+            relay.nombre, relay.ciclos.cuenta
+        );
     }
     if verbose {
-        println!("Relay on");
+        println!(
+            "Relay {} {}",
+            relay.nombre,
+            if encendido { "on" } else { "off" }
+        );
+    }
+    poner_nivel(encendido, relay);
+}
+
+//Sin contar ciclos
+fn poner_nivel(encendido: bool, relay: &mut Relay) {
+    if encendido == relay.active_low {
+        relay.pin.set_low();
+    } else {
+        relay.pin.set_high();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pulso(ms: u64, eco: [u8; 2]) -> (Pulso, oneshot::Receiver<Vec<u8>>) {
+        let (respuesta, rx) = oneshot::channel();
+        let pulso = Pulso {
+            fin: Instant::now() + Duration::from_millis(ms),
+            respuesta,
+            eco,
+        };
+        (pulso, rx)
+    }
+
+    #[test]
+    fn el_pulso_vence_a_su_tiempo() {
+        let (pulso, _rx) = pulso(100, [0, 100]);
+        assert!(!pulso.vencido(Instant::now()));
+        assert!(pulso.vencido(Instant::now() + Duration::from_millis(100)));
+    }
+
+    #[test]
+    fn soltar_el_pulso_responde_el_eco() {
+        let (pulso, mut rx) = pulso(100, [0x01, 0xF4]);
+        assert!(rx.try_recv().is_err());
+        pulso.soltar();
+        assert_eq!(rx.try_recv(), Ok(vec![0x01, 0xF4]));
+    }
+
+    #[test]
+    fn cortar_el_pulso_responde_error() {
+        let (pulso, mut rx) = pulso(100, [0x01, 0xF4]);
+        pulso.cortar();
+        assert_eq!(rx.try_recv(), Ok(vec![0xF0, 0xF0]));
+    }
+
+    #[test]
+    fn cada_encendido_cuenta_un_ciclo() {
+        let mut ciclos = Ciclos {
+            cuenta: 0,
+            fin_de_vida: Some(2),
+        };
+        assert!(!ciclos.contar());
+        assert!(!ciclos.gastado());
+        assert!(ciclos.contar());
+        assert!(ciclos.gastado());
+        //Solo avisa al llegar, no en cada ciclo de mas
+        assert!(!ciclos.contar());
+        assert!(ciclos.gastado());
+        assert_eq!(ciclos.cuenta, 3);
+    }
+
+    #[test]
+    fn sin_fin_de_vida_nunca_se_gasta() {
+        let mut ciclos = Ciclos {
+            cuenta: u32::MAX - 1,
+            fin_de_vida: None,
+        };
+        assert!(!ciclos.contar());
+        assert!(!ciclos.contar());
+        assert_eq!(ciclos.cuenta, u32::MAX);
+        assert!(!ciclos.gastado());
+    }
+
+    #[test]
+    fn desgaste_en_bytes() {
+        let ciclos = Ciclos {
+            cuenta: 0x0102,
+            fin_de_vida: Some(0x0102),
+        };
+        assert_eq!(ciclos.desgaste(true), [0, 0, 1, 2, 0, 0, 1, 2, 0b11]);
+        let ciclos = Ciclos {
+            cuenta: 5,
+            fin_de_vida: None,
+        };
+        assert_eq!(ciclos.desgaste(false), [0, 0, 0, 5, 0, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn el_estado_guardado_se_recupera() {
+        let archivo = std::env::temp_dir().join(format!("sspa_relays_{}.toml", std::process::id()));
+        let archivo = archivo.to_str().unwrap();
+        let guardado = BTreeMap::from([
+            (String::from("reset"), Guardado { on: true }),
+            (String::from("program"), Guardado { on: false }),
+        ]);
+        assert!(guardar(archivo, &guardado, false));
+        let cargado: BTreeMap<String, Guardado> = cargar(archivo, false);
+        assert_eq!(cargado.len(), 2);
+        assert!(cargado["reset"].on);
+        assert!(!cargado["program"].on);
+
+        //Un archivo roto no restaura nada
+        std::fs::write(archivo, "reset = 3").unwrap();
+        let cargado: BTreeMap<String, Guardado> = cargar(archivo, false);
+        assert!(cargado.is_empty());
+        let _ = std::fs::remove_file(archivo);
     }
-    pin.set_low();
 }
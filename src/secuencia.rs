@@ -0,0 +1,285 @@
+use tokio::time::{sleep, Duration};
+
+use crate::config::{Config, Step};
+use crate::dac::{self, dac_write};
+use crate::relay::relay_por_nombre;
+use crate::server::Canales;
+use crate::spi::escribir_pic;
+use crate::tnr::{señal_valida, tnr};
+
+/* SECUENCIAS DE ENCENDIDO */
+//msg: [opcode, indice], o [opcode, 0x80] con el nombre en la carga
+pub async fn secuencia(
+    msg: u32,
+    carga: Vec<u8>,
+    canales: &mut Canales,
+    config: &Config,
+    hat: bool,
+    verbose: bool,
+) -> [u8; 2] {
+    let (nombre, indice) = if msg & 0x00800000 != 0 {
+        let nombre = String::from_utf8_lossy(&carga).trim().to_string();
+        let indice = config.sequence.iter().position(|s| s.name == nombre);
+        (nombre, indice)
+    } else {
+        let indice = ((msg >> 16) & 0x7F) as usize;
+        (indice.to_string(), Some(indice))
+    };
+    let secuencia = match indice.and_then(|i| config.sequence.get(i)) {
+        Some(s) => s,
+        None => {
+            if verbose {
+                println!("Sequence {} not defined", nombre);
+            }
+            return [0xF0, 0xF0];
+        }
+    };
+
+    if verbose {
+        println!("Running sequence {}", secuencia.name);
+    }
+
+    for (n, paso) in secuencia.steps.iter().enumerate() {
+        if let Err(e) = ejecutar_paso(paso, canales, config, hat).await {
+            if verbose {
+                println!("Sequence {} aborted at step {}: {}", secuencia.name, n, e);
+            }
+            return [0xF0, n as u8];
+        }
+    }
+
+    if verbose {
+        println!("Sequence {} done", secuencia.name);
+    }
+    [0, secuencia.steps.len() as u8]
+}
+
+async fn ejecutar_paso(
+    paso: &Step,
+    canales: &mut Canales,
+    config: &Config,
+    hat: bool,
+) -> Result<(), String> {
+    match paso {
+        Step::PowerEnable { value } => {
+            escribir_tnr(5, *value as u16, canales).await?;
+        }
+        Step::Relay {
+            relay: nombre,
+            value,
+        } => {
+            let respuesta =
+                relay_por_nombre(*value as u32, nombre, &config.relay, &canales.relay_tx).await;
+            if respuesta == [0xF0, 0xF0] {
+                return Err(format!("unknown relay {}", nombre));
+            }
+            if respuesta != [0, *value as u8] {
+                return Err(format!("relay {} did not switch", nombre));
+            }
+        }
+        Step::Dac { channel, code } => {
+            let bits = dac::bits(config, hat);
+            if *channel > 7 || *code as u32 >= 1 << bits {
+                return Err(format!(
+                    "dac channel {} code {} out of range",
+                    channel, code
+                ));
+            }
+            let msg = 0x2A000000 | (*channel as u32) << 16 | *code as u32;
+            let respuesta = dac_write(msg, &mut canales.dac_rx, &canales.dac_tx).await;
+            //Con 16 bits no hay lugar para marcar la respuesta invalida
+            if respuesta == [0xF0, 0xF0] || (bits < 16 && respuesta[0] & 0x80 != 0) {
+                return Err(format!("dac channel {} invalid response", channel));
+            }
+        }
+        Step::TnrRegister { address, value } => {
+            if *address > 5 {
+                return Err(format!("tnr register {} out of range", address));
+            }
+            escribir_tnr(*address, *value, canales).await?;
+        }
+        Step::TnrStart => {
+            let mut registros = [0; 4];
+            for (direccion, registro) in registros.iter_mut().enumerate() {
+                let msg = 0x33000000 | (direccion as u32) << 16;
+                *registro =
+                    u16::from_be_bytes(tnr(msg, &mut canales.tnr_rx, &canales.tnr_tx).await);
+            }
+            if !señal_valida(registros) {
+                return Err(format!(
+                    "tnr registers {:?} are not a valid signal",
+                    registros
+                ));
+            }
+            tnr(0xA3000000, &mut canales.tnr_rx, &canales.tnr_tx).await;
+        }
+        Step::SpiWrite { address, value } => {
+            if *value > 0x7FFF {
+                return Err(format!("spi value {:X} out of range", value));
+            }
+            let msg = 0x25000000 | (*address as u32) << 16 | *value as u32;
+            if let Err(respuesta) = escribir_pic(msg, &mut canales.spi_rx, &canales.spi_tx).await {
+                return Err(format!("spi parity error in {:04X}", respuesta));
+            }
+        }
+        Step::Delay { ms } => {
+            sleep(Duration::from_millis(*ms)).await;
+        }
+    }
+    Ok(())
+}
+
+//El TnR responde con el valor que quedo en el registro
+async fn escribir_tnr(direccion: u8, valor: u16, canales: &mut Canales) -> Result<(), String> {
+    let msg = 0x23000000 | (direccion as u32) << 16 | valor as u32;
+    let respuesta = tnr(msg, &mut canales.tnr_rx, &canales.tnr_tx).await;
+    if respuesta != valor.to_be_bytes() {
+        return Err(format!(
+            "tnr register {} reads {:04X} after writing {:04X}",
+            direccion,
+            u16::from_be_bytes(respuesta),
+            valor
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    use crate::{calibracion, firmware, programador, traza};
+
+    //TnR y banco de relays de prueba, el resto de los canales no se usa
+    fn canales(config: &Config) -> Canales {
+        let (tnr_tx, mut tnr_peticiones) = tokio::sync::mpsc::channel::<[u8; 4]>(1);
+        let (tnr_respuestas, tnr_rx) = tokio::sync::broadcast::channel(16);
+        tokio::spawn(async move {
+            let mut registros = [0u16; 6];
+            while let Some(msg) = tnr_peticiones.recv().await {
+                let direccion = (msg[1] as usize).min(5);
+                if msg[0] == 0x23 {
+                    registros[direccion] = u16::from_be_bytes([msg[2], msg[3]]);
+                }
+                let _ = tnr_respuestas.send(registros[direccion].to_be_bytes());
+            }
+        });
+        let (relay_tx, mut relay_peticiones) =
+            tokio::sync::mpsc::channel::<crate::relay::PeticionRelay>(1);
+        tokio::spawn(async move {
+            while let Some((msg, tx)) = relay_peticiones.recv().await {
+                let _ = tx.send(vec![0, (msg[3] != 0) as u8]);
+            }
+        });
+        let (spi_tx, _) = tokio::sync::mpsc::channel(1);
+        let (dac_tx, _) = tokio::sync::mpsc::channel(1);
+        let (monitor_tx, _) = tokio::sync::mpsc::channel(1);
+        Canales {
+            spi_rx: tokio::sync::broadcast::channel(1).1,
+            spi_tx,
+            dac_rx: tokio::sync::broadcast::channel(1).1,
+            dac_tx,
+            tnr_rx,
+            tnr_tx,
+            relay_tx,
+            monitor_rx: tokio::sync::broadcast::channel(1).1,
+            monitor_tx,
+            apagado_rx: tokio::sync::broadcast::channel(1).1,
+            seguro_tx: tokio::sync::broadcast::channel(1).0,
+            exclusion: Arc::new(tokio::sync::RwLock::new(())),
+            traza: Arc::new(Mutex::new(traza::Traza::new(&config.spi_trace))),
+            programador: Arc::new(Mutex::new(programador::Programador::default())),
+            firmware: Arc::new(Mutex::new(firmware::Firmware::default())),
+            calibracion: Arc::new(Mutex::new(calibracion::Calibracion::new(
+                &config.dac,
+                dac::bits(config, false),
+                false,
+            ))),
+            registros: Arc::new(Vec::new()),
+        }
+    }
+
+    fn config() -> Config {
+        toml::from_str(
+            r#"
+            [[relay]]
+            name = "reset"
+            pin = 12
+
+            [[sequence]]
+            name = "encender"
+            steps = [
+                { action = "power_enable", value = true },
+                { action = "tnr_register", address = 0, value = 100 },
+                { action = "tnr_register", address = 1, value = 50 },
+                { action = "tnr_register", address = 2, value = 10 },
+                { action = "tnr_register", address = 3, value = 10 },
+                { action = "tnr_start" },
+                { action = "relay", relay = "reset", value = true },
+            ]
+
+            [[sequence]]
+            name = "sin_periodo"
+            steps = [
+                { action = "tnr_register", address = 0, value = 0 },
+                { action = "tnr_start" },
+            ]
+
+            [[sequence]]
+            name = "relay_desconocido"
+            steps = [{ action = "relay", relay = "program", value = true }]
+            "#,
+        )
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn corre_una_secuencia_por_indice_o_por_nombre() {
+        let config = config();
+        let mut canales = canales(&config);
+        assert_eq!(
+            secuencia(0x53000000, Vec::new(), &mut canales, &config, false, false).await,
+            [0, 7]
+        );
+        let nombre = b"encender".to_vec();
+        let msg = 0x53800000 | nombre.len() as u32;
+        assert_eq!(
+            secuencia(msg, nombre, &mut canales, &config, false, false).await,
+            [0, 7]
+        );
+    }
+
+    #[tokio::test]
+    async fn se_corta_en_el_paso_que_falla() {
+        let config = config();
+        let mut canales = canales(&config);
+        assert_eq!(
+            secuencia(0x53010000, Vec::new(), &mut canales, &config, false, false).await,
+            [0xF0, 1]
+        );
+        assert_eq!(
+            secuencia(0x53020000, Vec::new(), &mut canales, &config, false, false).await,
+            [0xF0, 0]
+        );
+        assert_eq!(
+            secuencia(0x53030000, Vec::new(), &mut canales, &config, false, false).await,
+            [0xF0, 0xF0]
+        );
+        let nombre = b"apagar".to_vec();
+        assert_eq!(
+            secuencia(0x53800006, nombre, &mut canales, &config, false, false).await,
+            [0xF0, 0xF0]
+        );
+    }
+
+    #[test]
+    fn señales_validas() {
+        assert!(señal_valida([100, 50, 10, 10]));
+        assert!(señal_valida([100, 100, 50, 50]));
+        assert!(!señal_valida([0, 0, 0, 0]));
+        assert!(!señal_valida([100, 101, 0, 0]));
+        assert!(!señal_valida([100, 50, 40, 11]));
+        assert!(!señal_valida([100, 50, u16::MAX, 1]));
+    }
+}
@@ -0,0 +1,35 @@
+use std::time::Duration;
+use tokio::signal::unix::{signal, SignalKind};
+
+//Cada handler suelta su copia una vez que dejó sus salidas en estado seguro
+pub type Listo = tokio::sync::mpsc::Sender<()>;
+
+pub async fn esperar_señal_de_apagado() {
+    let mut sigterm = signal(SignalKind::terminate()).expect("Falló escuchar SIGTERM");
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = sigterm.recv() => {}
+    }
+}
+
+pub async fn estado_seguro(
+    tx: &tokio::sync::broadcast::Sender<Listo>,
+    timeout: Duration,
+    verbose: bool,
+) {
+    if verbose {
+        println!("Setting outputs to safe state");
+    }
+    let (listo_tx, mut listo_rx) = tokio::sync::mpsc::channel(1);
+    if tx.send(listo_tx).is_err() {
+        return;
+    }
+
+    if tokio::time::timeout(timeout, listo_rx.recv())
+        .await
+        .is_err()
+        && verbose
+    {
+        println!("Timed out waiting for safe state");
+    }
+}
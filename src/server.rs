@@ -1,31 +1,100 @@
 use std::process::Command;
+use std::sync::Arc;
+use std::time::Duration;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::{TcpListener, TcpStream};
 
-use crate::dac::{dac_read, dac_write};
-use crate::relay::relay;
-use crate::spi::{spi_debug, spi_read, spi_write, spi_stress_test};
+use crate::calibracion::{dac_calibration, dac_millivolts, CalibracionDac};
+use crate::config::Config;
+use crate::dac::{
+    dac_read, dac_spi_config, dac_status, dac_waveform, dac_waveform_status, dac_write,
+    dac_write_channels, PeticionDac,
+};
+use crate::firmware::{firmware_check, firmware_upload, FirmwareSubido};
+use crate::programador::{pic_program, pic_program_status, ProgramadorPic};
+use crate::registros::{pic_dump, pic_register, MapaRegistros};
+use crate::relay::{relay, relay_por_nombre, relay_status, PeticionRelay};
+use crate::secuencia::secuencia;
+use crate::seguro::{estado_seguro, Listo};
+use crate::spi::{
+    spi_config, spi_debug, spi_parity, spi_read, spi_stress, spi_stress_test, spi_sweep,
+    spi_transfer, spi_write, PeticionSpi,
+};
 use crate::tnr::tnr;
 use crate::tnr_monitor::tnr_monitor;
+use crate::traza::{spi_trace, spi_trace_download, TrazaSpi};
+use crate::watchdog::Watchdog;
+
+//Las respuestas de largo variable van precedidas por su largo en 4 bytes
+pub enum Respuesta {
+    Palabra([u8; 2]),
+    Trama(Vec<u8>),
+}
+
+impl From<[u8; 2]> for Respuesta {
+    fn from(valor: [u8; 2]) -> Self {
+        Respuesta::Palabra(valor)
+    }
+}
+
+impl From<Vec<u8>> for Respuesta {
+    fn from(datos: Vec<u8>) -> Self {
+        Respuesta::Trama(datos)
+    }
+}
+
+pub struct Canales {
+    pub spi_rx: tokio::sync::broadcast::Receiver<Vec<u8>>,
+    pub spi_tx: tokio::sync::mpsc::Sender<PeticionSpi>,
+    pub dac_rx: tokio::sync::broadcast::Receiver<Vec<u8>>,
+    pub dac_tx: tokio::sync::mpsc::Sender<PeticionDac>,
+    pub tnr_rx: tokio::sync::broadcast::Receiver<[u8; 2]>,
+    pub tnr_tx: tokio::sync::mpsc::Sender<[u8; 4]>,
+    pub relay_tx: tokio::sync::mpsc::Sender<PeticionRelay>,
+    pub monitor_rx: tokio::sync::broadcast::Receiver<[u8; 2]>,
+    pub monitor_tx: tokio::sync::mpsc::Sender<[u8; 4]>,
+    pub apagado_rx: tokio::sync::broadcast::Receiver<()>,
+    pub seguro_tx: tokio::sync::broadcast::Sender<Listo>,
+    pub exclusion: Arc<tokio::sync::RwLock<()>>,
+    pub traza: TrazaSpi,
+    pub programador: ProgramadorPic,
+    pub firmware: FirmwareSubido,
+    pub calibracion: CalibracionDac,
+    pub registros: MapaRegistros,
+}
+
+impl Canales {
+    pub fn resubscribe(&self) -> Canales {
+        Canales {
+            spi_rx: self.spi_rx.resubscribe(),
+            spi_tx: self.spi_tx.clone(),
+            dac_rx: self.dac_rx.resubscribe(),
+            dac_tx: self.dac_tx.clone(),
+            tnr_rx: self.tnr_rx.resubscribe(),
+            tnr_tx: self.tnr_tx.clone(),
+            relay_tx: self.relay_tx.clone(),
+            monitor_rx: self.monitor_rx.resubscribe(),
+            monitor_tx: self.monitor_tx.clone(),
+            apagado_rx: self.apagado_rx.resubscribe(),
+            seguro_tx: self.seguro_tx.clone(),
+            exclusion: self.exclusion.clone(),
+            traza: self.traza.clone(),
+            programador: self.programador.clone(),
+            firmware: self.firmware.clone(),
+            calibracion: self.calibracion.clone(),
+            registros: self.registros.clone(),
+        }
+    }
+}
 
 pub async fn run(
     verbose: bool,
     quiet: bool,
     port: &str,
-    spi_rx: tokio::sync::broadcast::Receiver<[u8; 2]>,
-    spi_tx: tokio::sync::mpsc::Sender<[u8; 5]>,
-    dac_rx: tokio::sync::broadcast::Receiver<[u8; 2]>,
-    dac_tx: tokio::sync::mpsc::Sender<[u8; 3]>,
-    tnr_rx: tokio::sync::broadcast::Receiver<[u8; 2]>,
-    tnr_tx: tokio::sync::mpsc::Sender<[u8; 4]>,
-    reset_relay_rx: tokio::sync::broadcast::Receiver<[u8; 2]>,
-    reset_relay_tx: tokio::sync::mpsc::Sender<[u8; 4]>,
-    program_relay_rx: tokio::sync::broadcast::Receiver<[u8; 2]>,
-    program_relay_tx: tokio::sync::mpsc::Sender<[u8; 4]>,
-    monitor_rx: tokio::sync::broadcast::Receiver<[u8; 2]>,
-    monitor_tx: tokio::sync::mpsc::Sender<[u8; 4]>,
+    canales: Canales,
     little_endian: bool,
     hat: bool,
+    config: Arc<Config>,
 ) {
     if verbose {
         println!("Server starting");
@@ -49,38 +118,18 @@ pub async fn run(
         if verbose {
             println!("Conection from: {:?}", addr);
         }
-        let spi_rx_clone = spi_rx.resubscribe();
-        let spi_tx_clone = spi_tx.clone();
-        let dac_rx_clone = dac_rx.resubscribe();
-        let dac_tx_clone = dac_tx.clone();
-        let tnr_rx_clone = tnr_rx.resubscribe();
-        let tnr_tx_clone = tnr_tx.clone();
-        let reset_relay_rx_clone = reset_relay_rx.resubscribe();
-        let reset_relay_tx_clone = reset_relay_tx.clone();
-        let program_relay_rx_clone = program_relay_rx.resubscribe();
-        let program_relay_tx_clone = program_relay_tx.clone();
-        let monitor_rx_clone = monitor_rx.resubscribe();
-        let monitor_tx_clone = monitor_tx.clone();
+        let canales_clone = canales.resubscribe();
+        let config_clone = config.clone();
 
         tokio::spawn(async move {
             handle_connection(
                 socket,
                 verbose,
                 quiet,
-                spi_rx_clone,
-                spi_tx_clone,
-                dac_rx_clone,
-                dac_tx_clone,
-                tnr_rx_clone,
-                tnr_tx_clone,
-                reset_relay_rx_clone,
-                reset_relay_tx_clone,
-                program_relay_rx_clone,
-                program_relay_tx_clone,
-                monitor_rx_clone,
-                monitor_tx_clone,
+                canales_clone,
                 little_endian,
                 hat,
+                config_clone,
             )
             .await;
         });
@@ -91,28 +140,37 @@ async fn handle_connection(
     mut socket: TcpStream,
     verbose: bool,
     quiet: bool,
-    mut spi_rx: tokio::sync::broadcast::Receiver<[u8; 2]>,
-    spi_tx: tokio::sync::mpsc::Sender<[u8; 5]>,
-    mut dac_rx: tokio::sync::broadcast::Receiver<[u8; 2]>,
-    dac_tx: tokio::sync::mpsc::Sender<[u8; 3]>,
-    mut tnr_rx: tokio::sync::broadcast::Receiver<[u8; 2]>,
-    tnr_tx: tokio::sync::mpsc::Sender<[u8; 4]>,
-    mut reset_relay_rx: tokio::sync::broadcast::Receiver<[u8; 2]>,
-    reset_relay_tx: tokio::sync::mpsc::Sender<[u8; 4]>,
-    mut program_relay_rx: tokio::sync::broadcast::Receiver<[u8; 2]>,
-    program_relay_tx: tokio::sync::mpsc::Sender<[u8; 4]>,
-    mut monitor_rx: tokio::sync::broadcast::Receiver<[u8; 2]>,
-    monitor_tx: tokio::sync::mpsc::Sender<[u8; 4]>,
+    mut canales: Canales,
     little_endian: bool,
     hat: bool,
+    config: Arc<Config>,
 ) {
+    let mut watchdog = Watchdog::new(&config.watchdog);
+    let timeout_seguro = Duration::from_millis(config.safe_state.timeout_ms);
+
     loop {
         let mut buffer = [0; 4];
 
-        let n_bytes = socket.read(&mut buffer).await.unwrap();
+        let n_bytes = tokio::select! {
+            n_bytes = socket.read(&mut buffer) => n_bytes.unwrap_or(0),
+            _ = watchdog.vencido(), if watchdog.con_timeout() => {
+                if !quiet {
+                    println!("Watchdog expired, closing connection");
+                }
+                estado_seguro(&canales.seguro_tx, timeout_seguro, verbose).await;
+                break;
+            }
+            _ = canales.apagado_rx.recv() => {
+                if verbose {
+                    println!("Closing connection");
+                }
+                break;
+            }
+        };
 
         if n_bytes != 4 {
             if n_bytes == 0 {
+                desconectar(&watchdog, &canales, timeout_seguro, quiet, verbose).await;
                 break;
             }
             if verbose {
@@ -129,18 +187,225 @@ async fn handle_connection(
         if !quiet {
             println!("Received: {:X}", mensaje);
         }
+        watchdog.alimentar();
+
+        let carga = if con_carga(mensaje) {
+            let mut carga = vec![0; (mensaje & 0xFFFF) as usize];
+            if socket.read_exact(&mut carga).await.is_err() {
+                desconectar(&watchdog, &canales, timeout_seguro, quiet, verbose).await;
+                break;
+            }
+            carga
+        } else {
+            Vec::new()
+        };
+
+        //Las secuencias y las pruebas del SPI no se intercalan con comandos de otras conexiones.
+        //Los comandos del programador y de firmware no esperan, se usan mientras programa
+        let exclusion = canales.exclusion.clone();
+        let _lectura;
+        let _escritura;
+        if exclusivo(mensaje) {
+            _escritura = exclusion.write().await;
+        } else if !matches!(mensaje & 0x7F000000, 0x46000000 | 0x50000000) {
+            _lectura = exclusion.read().await;
+        }
 
-        let respuesta = match mensaje & 0x7F000000 {
-            0x3C000000 => Some(spi_read(mensaje, &mut spi_rx, &spi_tx).await),
-            0x25000000 => Some(spi_write(mensaje, &mut spi_rx, &spi_tx).await),
-            0x5B000000 => Some(spi_debug(mensaje, &mut spi_rx, &spi_tx).await),
-            0x3A000000 => Some(dac_read(mensaje, &mut dac_rx, &dac_tx, hat).await),
-            0x2A000000 => Some(dac_write(mensaje, &mut dac_rx, &dac_tx, hat).await),
-            0x33000000 | 0x23000000 | 0xA3000000 => Some(tnr(mensaje, &mut tnr_rx, &tnr_tx).await),
-            0x2D000000 => Some(relay(mensaje, &mut reset_relay_rx, &reset_relay_tx).await),
-            0x3D000000 => Some(relay(mensaje, &mut program_relay_rx, &program_relay_tx).await),
-            0x4D000000 => Some(tnr_monitor(mensaje, &mut monitor_rx, &monitor_tx).await),
-            0x5E000000 => Some(spi_stress_test(mensaje, &mut spi_rx, &spi_tx, verbose).await),
+        let respuesta: Option<Respuesta> = match mensaje & 0x7F000000 {
+            0x3C000000 => Some(
+                spi_read(mensaje, &mut canales.spi_rx, &canales.spi_tx)
+                    .await
+                    .into(),
+            ),
+            0x25000000 => Some(
+                spi_write(mensaje, &mut canales.spi_rx, &canales.spi_tx)
+                    .await
+                    .into(),
+            ),
+            0x43000000 if es_dac(mensaje, &config) => Some(
+                dac_spi_config(mensaje, &mut canales.dac_rx, &canales.dac_tx)
+                    .await
+                    .into(),
+            ),
+            0x43000000 => Some(
+                spi_config(mensaje, &mut canales.spi_rx, &canales.spi_tx)
+                    .await
+                    .into(),
+            ),
+            0x45000000 => Some(
+                spi_parity(mensaje, &mut canales.spi_rx, &canales.spi_tx)
+                    .await
+                    .into(),
+            ),
+            0x44000000 => Some(
+                pic_dump(
+                    mensaje,
+                    &config.register_dump,
+                    &canales.registros,
+                    &mut canales.spi_rx,
+                    &canales.spi_tx,
+                    verbose,
+                )
+                .await
+                .into(),
+            ),
+            0x46000000 if (mensaje >> 20) & 0x0F >= 2 => {
+                Some(firmware_check(mensaje, &canales.firmware, &config.firmware, verbose).into())
+            }
+            0x46000000 => Some(
+                firmware_upload(mensaje, carga, &canales.firmware, &config.firmware, verbose)
+                    .into(),
+            ),
+            0x50000000 if (mensaje >> 20) & 0x0F == 3 => Some(pic_program_status(&canales).into()),
+            0x50000000 => Some(pic_program(mensaje, carga, &canales, &config, verbose).into()),
+            0x52000000 => Some(
+                pic_register(
+                    carga,
+                    &canales.registros,
+                    &mut canales.spi_rx,
+                    &canales.spi_tx,
+                    verbose,
+                )
+                .await
+                .into(),
+            ),
+            0x54000000 => Some(
+                spi_transfer(mensaje, carga, &mut canales.spi_rx, &canales.spi_tx)
+                    .await
+                    .into(),
+            ),
+            0x57000000 if (3..=5).contains(&((mensaje >> 20) & 0x0F)) => Some(
+                spi_trace_download(mensaje, &canales.traza, config.spi_trace.max_download_size)
+                    .await
+                    .into(),
+            ),
+            0x57000000 => Some(spi_trace(mensaje, &canales.traza, verbose).into()),
+            0x5B000000 => Some(
+                spi_debug(mensaje, &mut canales.spi_rx, &canales.spi_tx)
+                    .await
+                    .into(),
+            ),
+            0x3A000000 => Some(
+                dac_read(mensaje, &mut canales.dac_rx, &canales.dac_tx)
+                    .await
+                    .into(),
+            ),
+            0x3B000000 => Some(
+                dac_status(mensaje, &mut canales.dac_rx, &canales.dac_tx)
+                    .await
+                    .into(),
+            ),
+            0x2A000000 => Some(
+                dac_write(mensaje, &mut canales.dac_rx, &canales.dac_tx)
+                    .await
+                    .into(),
+            ),
+            0x2C000000 => Some(
+                dac_write_channels(carga, &mut canales.dac_rx, &canales.dac_tx, verbose)
+                    .await
+                    .into(),
+            ),
+            0x2B000000 => Some(
+                dac_millivolts(
+                    mensaje,
+                    &canales.calibracion,
+                    &mut canales.dac_rx,
+                    &canales.dac_tx,
+                    verbose,
+                )
+                .await
+                .into(),
+            ),
+            0x4C000000 => Some(
+                dac_calibration(
+                    mensaje,
+                    &canales.calibracion,
+                    &mut canales.dac_rx,
+                    &canales.dac_tx,
+                    verbose,
+                )
+                .await
+                .into(),
+            ),
+            0x47000000 if (mensaje >> 20) & 0x0F == 2 => Some(
+                dac_waveform_status(mensaje, &mut canales.dac_rx, &canales.dac_tx)
+                    .await
+                    .into(),
+            ),
+            0x47000000 => Some(
+                dac_waveform(
+                    mensaje,
+                    carga,
+                    &mut canales.dac_rx,
+                    &canales.dac_tx,
+                    verbose,
+                )
+                .await
+                .into(),
+            ),
+            0x33000000 | 0x23000000 | 0xA3000000 => Some(
+                tnr(mensaje, &mut canales.tnr_rx, &canales.tnr_tx)
+                    .await
+                    .into(),
+            ),
+            0x2D000000 => Some(
+                relay_por_nombre(mensaje, "reset", &config.relay, &canales.relay_tx)
+                    .await
+                    .into(),
+            ),
+            0x3D000000 => Some(
+                relay_por_nombre(mensaje, "program", &config.relay, &canales.relay_tx)
+                    .await
+                    .into(),
+            ),
+            0x2E000000 if mensaje & 0x00F00000 == 0x00400000 => {
+                Some(relay_status(&canales.relay_tx).await.into())
+            }
+            0x2E000000 => Some(relay(mensaje, &canales.relay_tx).await.into()),
+            0x4D000000 => Some(
+                tnr_monitor(mensaje, &mut canales.monitor_rx, &canales.monitor_tx)
+                    .await
+                    .into(),
+            ),
+            0x48000000 => Some([0, watchdog.armado() as u8].into()),
+            0x53000000 => Some(
+                secuencia(mensaje, carga, &mut canales, &config, hat, verbose)
+                    .await
+                    .into(),
+            ),
+            0x5E000000 => Some(
+                spi_stress_test(
+                    mensaje,
+                    config.spi_test.scratch_register,
+                    &mut canales.spi_rx,
+                    &canales.spi_tx,
+                    verbose,
+                )
+                .await
+                .into(),
+            ),
+            0x5C000000 => Some(
+                spi_sweep(
+                    carga,
+                    &config.spi_test,
+                    &mut canales.spi_rx,
+                    &canales.spi_tx,
+                    verbose,
+                )
+                .await
+                .into(),
+            ),
+            0x5F000000 => Some(
+                spi_stress(
+                    carga,
+                    config.spi_test.scratch_register,
+                    &mut canales.spi_rx,
+                    &canales.spi_tx,
+                    verbose,
+                )
+                .await
+                .into(),
+            ),
             _ => {
                 if verbose {
                     println!("Invalid Command");
@@ -149,20 +414,88 @@ async fn handle_connection(
             }
         };
 
-        if let Some(valor) = respuesta {
-            let valor = invertir(valor, little_endian);
-            let _ = socket.write_all(&valor).await;
-            if !quiet {
-                println!(
-                    "Sent: {:X}",
-                    if little_endian {
-                        <u16>::from_le_bytes(valor)
-                    } else {
-                        <u16>::from_be_bytes(valor)
-                    }
-                );
+        if respuesta.is_some() {
+            watchdog.armar(mensaje);
+        }
+
+        match respuesta {
+            Some(Respuesta::Palabra(valor)) => {
+                let valor = invertir(valor, little_endian);
+                let _ = socket.write_all(&valor).await;
+                if !quiet {
+                    println!(
+                        "Sent: {:X}",
+                        if little_endian {
+                            <u16>::from_le_bytes(valor)
+                        } else {
+                            <u16>::from_be_bytes(valor)
+                        }
+                    );
+                }
+            }
+            Some(Respuesta::Trama(datos)) => {
+                let largo = if little_endian {
+                    (datos.len() as u32).to_le_bytes()
+                } else {
+                    (datos.len() as u32).to_be_bytes()
+                };
+                let _ = socket.write_all(&largo).await;
+                let _ = socket.write_all(&datos).await;
+                if !quiet {
+                    println!("Sent: {} bytes", datos.len());
+                }
             }
+            None => {}
+        }
+    }
+}
+
+//Comandos seguidos por tantos bytes como indiquen sus 16 bits bajos
+//El dispositivo SPI del comando es el del DAC, que no esta en el handler del SPI
+fn es_dac(mensaje: u32, config: &Config) -> bool {
+    config
+        .spi_device
+        .get(((mensaje >> 20) & 0x0F) as usize)
+        .is_some_and(|d| d.name == "dac")
+}
+
+fn con_carga(mensaje: u32) -> bool {
+    //Las secuencias por nombre llevan el nombre como carga
+    if mensaje & 0x7F800000 == 0x53800000 {
+        return true;
+    }
+    matches!(
+        mensaje & 0x7F000000,
+        0x2C000000
+            | 0x46000000
+            | 0x47000000
+            | 0x50000000
+            | 0x52000000
+            | 0x54000000
+            | 0x5C000000
+            | 0x5F000000
+    )
+}
+
+fn exclusivo(mensaje: u32) -> bool {
+    matches!(
+        mensaje & 0x7F000000,
+        0x53000000 | 0x5C000000 | 0x5E000000 | 0x5F000000
+    )
+}
+
+async fn desconectar(
+    watchdog: &Watchdog,
+    canales: &Canales,
+    timeout_seguro: Duration,
+    quiet: bool,
+    verbose: bool,
+) {
+    if watchdog.armado() {
+        if !quiet {
+            println!("Client disconnected with outputs set");
         }
+        estado_seguro(&canales.seguro_tx, timeout_seguro, verbose).await;
     }
 }
 
@@ -1,119 +1,998 @@
+use std::sync::{Arc, Mutex};
 use std::thread::sleep;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-use rppal::spi::*;
+use rppal::spi::{self, Bus, Mode, SlaveSelect, Spi};
 
-const SPI_INTER_TRANSACTION_GAP: Duration = Duration::from_micros(100);
+use crate::config::{self, BitOrder};
+use crate::traza::{Transaccion, TrazaSpi};
+
+//Limite de spidev por transferencia
+const SPI_MAX_TRANSFER: usize = 4096;
+
+//Con semilla 0 el xorshift no sale nunca de 0
+const SEMILLA_POR_DEFECTO: u32 = 0x2545F491;
+
+#[derive(Debug)]
+pub enum PeticionSpi {
+    //[largo, palabra alta, palabra baja]
+    Palabras([u8; 5]),
+    //Una sola trama con el chip select activo en el dispositivo indicado,
+    //verificando la paridad de lo recibido si se pide
+    Transferencia(usize, Vec<u8>, bool),
+    //Dispositivo, comando y valor
+    Configurar(usize, u8, u16),
+    //Estadisticas de paridad, borrandolas si se pide
+    Paridad(bool),
+    //Reloj del PIC en Hz, 0 para dejarlo como esta
+    RelojPic(u32),
+}
+
+//Errores de paridad en las respuestas del PIC
+#[derive(Default)]
+struct ErroresParidad {
+    palabras: u32,
+    errores: u32,
+    ultima_erronea: u16,
+}
+
+impl ErroresParidad {
+    //Devuelve la cantidad de palabras con error
+    fn verificar(&mut self, datos: &[u8], verbose: bool) -> u32 {
+        let mut errores = 0;
+        self.palabras = self.palabras.wrapping_add((datos.len() / 2) as u32);
+        for palabra in palabras_erroneas(datos) {
+            if verbose {
+                println!("Parity error in {:04X}", palabra);
+            }
+            errores += 1;
+            self.ultima_erronea = palabra;
+        }
+        self.errores = self.errores.saturating_add(errores);
+        errores
+    }
+}
+
+//Palabras de 16 bits con paridad impar
+fn palabras_erroneas(datos: &[u8]) -> impl Iterator<Item = u16> + '_ {
+    datos
+        .chunks_exact(2)
+        .map(|p| u16::from_be_bytes([p[0], p[1]]))
+        .filter(|p| !p.count_ones().is_multiple_of(2))
+}
+
+/* DISPOSITIVO DEL REGISTRO SPI */
+//Las transferencias son bloqueantes y se hacen fuera del runtime con spawn_blocking
+pub struct Dispositivo {
+    pub config: config::SpiDevice,
+    spi: Arc<Mutex<Spi>>,
+    traza: Option<TrazaSpi>,
+}
+
+impl Dispositivo {
+    pub fn abrir(config: &config::SpiDevice) -> spi::Result<Self> {
+        let bus = match config.bus {
+            0 => Bus::Spi0,
+            1 => Bus::Spi1,
+            2 => Bus::Spi2,
+            3 => Bus::Spi3,
+            4 => Bus::Spi4,
+            5 => Bus::Spi5,
+            _ => Bus::Spi6,
+        };
+        let slave_select = match config.slave_select {
+            0 => SlaveSelect::Ss0,
+            1 => SlaveSelect::Ss1,
+            2 => SlaveSelect::Ss2,
+            3 => SlaveSelect::Ss3,
+            4 => SlaveSelect::Ss4,
+            5 => SlaveSelect::Ss5,
+            6 => SlaveSelect::Ss6,
+            7 => SlaveSelect::Ss7,
+            8 => SlaveSelect::Ss8,
+            9 => SlaveSelect::Ss9,
+            10 => SlaveSelect::Ss10,
+            11 => SlaveSelect::Ss11,
+            12 => SlaveSelect::Ss12,
+            13 => SlaveSelect::Ss13,
+            14 => SlaveSelect::Ss14,
+            _ => SlaveSelect::Ss15,
+        };
+        let spi = Spi::new(bus, slave_select, config.clock_hz, modo(config.mode))?;
+
+        Ok(Dispositivo {
+            config: config.clone(),
+            spi: Arc::new(Mutex::new(spi)),
+            traza: None,
+        })
+    }
+
+    pub fn con_traza(mut self, traza: TrazaSpi) -> Self {
+        self.traza = Some(traza);
+        self
+    }
+
+    //Se abre con la nueva configuracion, si falla queda la anterior
+    pub fn reconfigurar(&mut self, config: config::SpiDevice) -> spi::Result<()> {
+        let mut nuevo = Dispositivo::abrir(&config)?;
+        nuevo.traza = self.traza.take();
+        *self = nuevo;
+        Ok(())
+    }
+
+    //El controlador del Pi solo manda MSB primero, el orden se invierte a mano.
+    //La pausa se hace en el mismo hilo bloqueante, el timer de tokio no baja de 1 ms
+    pub async fn transfer(&self, recibido: &mut [u8], datos: &[u8]) {
+        let instante = self.traza.as_ref().and_then(|traza| {
+            let traza = traza.lock().unwrap();
+            traza.activa().then(|| traza.instante_us())
+        });
+        let enviado = instante.map(|_| datos.to_vec());
+        let lsb_primero = self.config.bit_order == BitOrder::LsbFirst;
+        let datos: Vec<u8> = if lsb_primero {
+            datos.iter().map(|b| b.reverse_bits()).collect()
+        } else {
+            datos.to_vec()
+        };
+        let largo = recibido.len();
+        let pausa = Duration::from_micros(self.config.gap_us);
+        let spi = self.spi.clone();
+
+        let leido = tokio::task::spawn_blocking(move || {
+            let mut leido = vec![0; largo];
+            spi.lock().unwrap().transfer(&mut leido, &datos).unwrap();
+            sleep(pausa);
+            leido
+        })
+        .await
+        .unwrap();
+
+        recibido.copy_from_slice(&leido);
+        if lsb_primero {
+            recibido.iter_mut().for_each(|b| *b = b.reverse_bits());
+        }
+
+        if let (Some(traza), Some(instante_us), Some(enviado)) = (&self.traza, instante, enviado) {
+            let paridad =
+                (self.config.name == "pic").then(|| palabras_erroneas(recibido).next().is_none());
+            traza.lock().unwrap().registrar(Transaccion {
+                instante_us,
+                dispositivo: self.config.name.clone(),
+                reloj_hz: self.config.clock_hz,
+                modo: self.config.mode,
+                lsb_primero,
+                enviado,
+                recibido: recibido.to_vec(),
+                paridad,
+            });
+        }
+    }
+}
+
+fn modo(mode: u8) -> Mode {
+    match mode {
+        0 => Mode::Mode0,
+        1 => Mode::Mode1,
+        2 => Mode::Mode2,
+        _ => Mode::Mode3,
+    }
+}
 
 /* SPI CON EL PIC */
 pub async fn spi_handler(
     verbose: bool,
-    mut rx: tokio::sync::mpsc::Receiver<[u8; 5]>,
-    tx: tokio::sync::broadcast::Sender<[u8; 2]>,
-    mega_hertz: bool,
+    mut rx: tokio::sync::mpsc::Receiver<PeticionSpi>,
+    tx: tokio::sync::broadcast::Sender<Vec<u8>>,
+    dispositivos: Vec<config::SpiDevice>,
+    traza: TrazaSpi,
 ) {
-    let clock_speed = if mega_hertz { 1000000 } else { 100000 };
-    let spi =
-        Spi::new(Bus::Spi0, SlaveSelect::Ss0, clock_speed, Mode::Mode1).expect("Falló abrir spi");
+    //Sin el pic no se sigue, los demas que no abren quedan sin usar.
+    //El dac lo abre el handler del DAC, su lugar queda vacio para no correr los indices
+    let mut dispositivos: Vec<Option<Dispositivo>> = dispositivos
+        .iter()
+        .map(|d| {
+            if d.name == "dac" {
+                return None;
+            }
+            match Dispositivo::abrir(d) {
+                Ok(dispositivo) => Some(dispositivo.con_traza(traza.clone())),
+                Err(e) if d.name == "pic" => panic!("Falló abrir spi pic: {}", e),
+                Err(e) => {
+                    println!("Spi device {} not available: {}", d.name, e);
+                    None
+                }
+            }
+        })
+        .collect();
+    let pic = dispositivos
+        .iter()
+        .position(|d| d.as_ref().is_some_and(|d| d.config.name == "pic"))
+        .unwrap();
     let mut buffer = [0; 2];
+    let mut paridad = ErroresParidad::default();
 
     loop {
-        let msg = rx.recv().await.unwrap();
+        let msg = match rx.recv().await.unwrap() {
+            PeticionSpi::Palabras(msg) => msg,
+            PeticionSpi::Transferencia(indice, datos, verificar) => {
+                let dispositivo = match dispositivos.get(indice) {
+                    Some(Some(d)) => d,
+                    _ => {
+                        if verbose {
+                            println!("Spi device {} not available", indice);
+                        }
+                        tx.send(vec![0xF0]).unwrap();
+                        continue;
+                    }
+                };
+                let mut recibido = vec![0; datos.len() + 1];
+                dispositivo.transfer(&mut recibido[1..], &datos).await;
+                if verbose {
+                    println!("Spi {} sent: {:02X?}", dispositivo.config.name, datos);
+                    println!(
+                        "Spi {} got: {:02X?}",
+                        dispositivo.config.name,
+                        &recibido[1..]
+                    );
+                }
+                //Solo las respuestas del PIC van al contador
+                let erroneas = if !verificar {
+                    0
+                } else if indice == pic {
+                    paridad.verificar(&recibido[1..], verbose)
+                } else {
+                    palabras_erroneas(&recibido[1..]).count() as u32
+                };
+                if erroneas > 0 {
+                    recibido[0] = 0x01;
+                }
+                tx.send(recibido).unwrap();
+                continue;
+            }
+            PeticionSpi::Paridad(borrar) => {
+                let mut respuesta = paridad.errores.to_be_bytes().to_vec();
+                respuesta.extend_from_slice(&paridad.palabras.to_be_bytes());
+                respuesta.extend_from_slice(&paridad.ultima_erronea.to_be_bytes());
+                if borrar {
+                    paridad = ErroresParidad::default();
+                }
+                tx.send(respuesta).unwrap();
+                continue;
+            }
+            PeticionSpi::RelojPic(hz) => {
+                let dispositivo = dispositivos[pic].as_mut().unwrap();
+                let anterior = dispositivo.config.clock_hz;
+                let mut estado = 0;
+                if hz > 0 && hz != anterior {
+                    let mut nueva = dispositivo.config.clone();
+                    nueva.clock_hz = hz;
+                    if let Err(e) = dispositivo.reconfigurar(nueva) {
+                        if verbose {
+                            println!("Failed to set spi pic to {} Hz: {}", hz, e);
+                        }
+                        estado = 0xF0;
+                    }
+                }
+                let mut respuesta = vec![estado];
+                respuesta.extend_from_slice(&anterior.to_be_bytes());
+                tx.send(respuesta).unwrap();
+                continue;
+            }
+            PeticionSpi::Configurar(indice, comando, valor) => {
+                let respuesta = match dispositivos.get_mut(indice) {
+                    Some(Some(dispositivo)) => configurar(dispositivo, comando, valor, verbose),
+                    _ => {
+                        if verbose {
+                            println!("Spi device {} not available", indice);
+                        }
+                        vec![0xF0]
+                    }
+                };
+                tx.send(respuesta).unwrap();
+                continue;
+            }
+        };
+        let spi = dispositivos[pic].as_ref().unwrap();
 
-        spi.transfer(&mut buffer, &msg[1..3]).unwrap();
-        sleep(SPI_INTER_TRANSACTION_GAP);
+        spi.transfer(&mut buffer, &msg[1..3]).await;
         if verbose {
             println!("Spi sent: {:02X}{:02X}", msg[1], msg[2]);
             println!("Spi got: {:02X}{:02X}", buffer[0], buffer[1]);
         }
         if msg[0] > 1 {
-            spi.transfer(&mut buffer, &msg[3..5]).unwrap();
-            sleep(SPI_INTER_TRANSACTION_GAP);
+            spi.transfer(&mut buffer, &msg[3..5]).await;
             if verbose {
                 println!("Spi sent: {:02X}{:02X}", msg[3], msg[4]);
                 println!("Spi got: {:02X}{:02X}", buffer[0], buffer[1]);
             }
         }
         if msg[0] > 0 {
-            spi.transfer(&mut buffer, &[0; 2]).unwrap();
-            sleep(SPI_INTER_TRANSACTION_GAP);
+            spi.transfer(&mut buffer, &[0; 2]).await;
             if verbose {
                 println!("Spi sent: 0");
                 println!("Spi got: {:02X}{:02X}", buffer[0], buffer[1]);
             }
         }
 
-        tx.send(buffer).unwrap();
+        let estado = (paridad.verificar(&buffer, verbose) > 0) as u8;
+        tx.send(vec![buffer[0], buffer[1], estado]).unwrap();
+    }
+}
+
+//comando 0: reloj en kHz, 1: modo, 2: pausa entre transacciones en us, 3: consulta
+//Respuesta: [estado, reloj en Hz (u32), modo, orden de bits, pausa en us (u32)]
+pub fn configurar(
+    dispositivo: &mut Dispositivo,
+    comando: u8,
+    valor: u16,
+    verbose: bool,
+) -> Vec<u8> {
+    let mut nueva = dispositivo.config.clone();
+    match comando {
+        0 if valor > 0 => nueva.clock_hz = valor as u32 * 1000,
+        1 if valor <= 3 => nueva.mode = valor as u8,
+        2 => nueva.gap_us = valor as u64,
+        3 => {}
+        _ => {
+            if verbose {
+                println!("Invalid spi setting");
+            }
+            return vec![0xF0];
+        }
+    }
+
+    let mut estado = 0;
+    if comando < 3 {
+        if let Err(e) = dispositivo.reconfigurar(nueva) {
+            if verbose {
+                println!("Failed to reopen spi {}: {}", dispositivo.config.name, e);
+            }
+            estado = 0xF0;
+        } else if verbose {
+            println!(
+                "Spi {} set to {} Hz mode {} gap {} us",
+                dispositivo.config.name,
+                dispositivo.config.clock_hz,
+                dispositivo.config.mode,
+                dispositivo.config.gap_us
+            );
+        }
+    }
+
+    let config = &dispositivo.config;
+    let mut respuesta = vec![estado];
+    respuesta.extend_from_slice(&config.clock_hz.to_be_bytes());
+    respuesta.push(config.mode);
+    respuesta.push((config.bit_order == BitOrder::LsbFirst) as u8);
+    respuesta.extend_from_slice(&(config.gap_us as u32).to_be_bytes());
+    respuesta
+}
+
+pub async fn spi_config(
+    msg: u32,
+    rx: &mut tokio::sync::broadcast::Receiver<Vec<u8>>,
+    tx: &tokio::sync::mpsc::Sender<PeticionSpi>,
+) -> Vec<u8> {
+    let dispositivo = ((msg >> 20) & 0x0F) as usize;
+    let comando = ((msg >> 16) & 0x0F) as u8;
+    tx.send(PeticionSpi::Configurar(
+        dispositivo,
+        comando,
+        (msg & 0xFFFF) as u16,
+    ))
+    .await
+    .unwrap();
+    rx.recv().await.unwrap()
+}
+
+//Palabra del PIC, o con error de paridad la palabra recibida
+pub async fn leer_pic(
+    msg: u32,
+    rx: &mut tokio::sync::broadcast::Receiver<Vec<u8>>,
+    tx: &tokio::sync::mpsc::Sender<PeticionSpi>,
+) -> Result<u16, u16> {
+    spi_core(1, msg, rx, tx).await
+}
+
+pub async fn escribir_pic(
+    msg: u32,
+    rx: &mut tokio::sync::broadcast::Receiver<Vec<u8>>,
+    tx: &tokio::sync::mpsc::Sender<PeticionSpi>,
+) -> Result<u16, u16> {
+    spi_core(2, msg, rx, tx).await
+}
+
+//El bit 15 es la paridad, en la respuesta queda en 1 solo si hubo error
+//0ddddddddddddddd <- Respuesta valida
+//1ddddddddddddddd <- Error de paridad, con los datos recibidos
+fn marcar_paridad(respuesta: Result<u16, u16>) -> [u8; 2] {
+    match respuesta {
+        Ok(palabra) => (palabra & 0x7FFF).to_be_bytes(),
+        Err(palabra) => (palabra | 0x8000).to_be_bytes(),
     }
 }
 
 pub async fn spi_read(
     msg: u32,
-    rx: &mut tokio::sync::broadcast::Receiver<[u8; 2]>,
-    tx: &tokio::sync::mpsc::Sender<[u8; 5]>,
+    rx: &mut tokio::sync::broadcast::Receiver<Vec<u8>>,
+    tx: &tokio::sync::mpsc::Sender<PeticionSpi>,
 ) -> [u8; 2] {
-    spi_core(1, msg, rx, tx).await
+    marcar_paridad(leer_pic(msg, rx, tx).await)
 }
 
 pub async fn spi_write(
     msg: u32,
-    rx: &mut tokio::sync::broadcast::Receiver<[u8; 2]>,
-    tx: &tokio::sync::mpsc::Sender<[u8; 5]>,
+    rx: &mut tokio::sync::broadcast::Receiver<Vec<u8>>,
+    tx: &tokio::sync::mpsc::Sender<PeticionSpi>,
 ) -> [u8; 2] {
-    spi_core(2, msg, rx, tx).await
+    marcar_paridad(escribir_pic(msg, rx, tx).await)
 }
 
 pub async fn spi_debug(
     msg: u32,
-    rx: &mut tokio::sync::broadcast::Receiver<[u8; 2]>,
-    tx: &tokio::sync::mpsc::Sender<[u8; 5]>,
+    rx: &mut tokio::sync::broadcast::Receiver<Vec<u8>>,
+    tx: &tokio::sync::mpsc::Sender<PeticionSpi>,
 ) -> [u8; 2] {
-    spi_core(0, msg << 16, rx, tx).await
+    marcar_paridad(spi_core(0, msg << 16, rx, tx).await)
+}
+
+/* PRUEBAS DE ESCRITURA Y LECTURA */
+#[derive(Default)]
+pub struct Estadisticas {
+    pub transacciones: u32,
+    pub discrepancias: u32,
+    pub errores_paridad: u32,
+    pub duracion: Duration,
+}
+
+impl Estadisticas {
+    pub fn errores(&self) -> u32 {
+        self.discrepancias.saturating_add(self.errores_paridad)
+    }
+
+    pub fn por_segundo(&self) -> u32 {
+        let micros = self.duracion.as_micros().max(1);
+        (self.transacciones as u128 * 1_000_000 / micros) as u32
+    }
+}
+
+fn xorshift(estado: &mut u32) -> u32 {
+    *estado ^= *estado << 13;
+    *estado ^= *estado >> 17;
+    *estado ^= *estado << 5;
+    *estado
+}
+
+//Escribe patrones pseudoaleatorios de 15 bits en el registro y los lee de vuelta
+pub async fn verificar_registro(
+    cantidad: u32,
+    semilla: u32,
+    registro: u8,
+    rx: &mut tokio::sync::broadcast::Receiver<Vec<u8>>,
+    tx: &tokio::sync::mpsc::Sender<PeticionSpi>,
+) -> Estadisticas {
+    let mut estado = if semilla == 0 {
+        SEMILLA_POR_DEFECTO
+    } else {
+        semilla
+    };
+    let direccion = (registro as u32) << 16;
+    let mut estadisticas = Estadisticas::default();
+    let inicio = Instant::now();
+
+    for _ in 0..cantidad {
+        let patron = (xorshift(&mut estado) & 0x7FFF) as u16;
+        let escrito = escribir_pic(0x25000000 | direccion | patron as u32, rx, tx).await;
+        let leido = leer_pic(0x3C000000 | direccion, rx, tx).await;
+        estadisticas.transacciones = estadisticas.transacciones.saturating_add(2);
+        for palabra in [escrito, leido] {
+            if palabra.is_err() {
+                estadisticas.errores_paridad = estadisticas.errores_paridad.saturating_add(1);
+            }
+        }
+        let (Ok(leido) | Err(leido)) = leido;
+        if leido & 0x7FFF != patron {
+            estadisticas.discrepancias = estadisticas.discrepancias.saturating_add(1);
+        }
+    }
+
+    estadisticas.duracion = inicio.elapsed();
+    estadisticas
 }
 
+//Cambia el reloj del PIC y devuelve el que tenia, None si no se pudo cambiar
+async fn reloj_pic(
+    hz: u32,
+    rx: &mut tokio::sync::broadcast::Receiver<Vec<u8>>,
+    tx: &tokio::sync::mpsc::Sender<PeticionSpi>,
+) -> Option<u32> {
+    tx.send(PeticionSpi::RelojPic(hz)).await.unwrap();
+    let respuesta = rx.recv().await.unwrap();
+    if respuesta[0] != 0 {
+        return None;
+    }
+    Some(u32::from_be_bytes([
+        respuesta[1],
+        respuesta[2],
+        respuesta[3],
+        respuesta[4],
+    ]))
+}
+
+//Version corta: cantidad en los 16 bits bajos, con la semilla y el reloj por defecto
+//Respuesta: errores encontrados. Sin registro para escribir solo lee, como antes,
+//y responde la ultima lectura
 pub async fn spi_stress_test(
     msg: u32,
-    rx: &mut tokio::sync::broadcast::Receiver<[u8; 2]>,
-    tx: &tokio::sync::mpsc::Sender<[u8; 5]>,
+    registro: Option<u8>,
+    rx: &mut tokio::sync::broadcast::Receiver<Vec<u8>>,
+    tx: &tokio::sync::mpsc::Sender<PeticionSpi>,
     verbose: bool,
 ) -> [u8; 2] {
     let pack_count = msg & 0x0000FFFF;
     if verbose {
         println!("Stress testing with {} packets", pack_count);
     }
-    for _ in 0..pack_count {
-        spi_core(1, 0, rx, tx).await;
+    let Some(registro) = registro else {
+        for _ in 0..pack_count {
+            let _ = leer_pic(0, rx, tx).await;
+        }
+        if verbose {
+            println!("Stress testing finished");
+        }
+        return marcar_paridad(leer_pic(0, rx, tx).await);
+    };
+    let estadisticas = verificar_registro(pack_count, 0, registro, rx, tx).await;
+    if verbose {
+        println!("Stress testing finished, {} errors", estadisticas.errores());
+    }
+    (estadisticas.errores().min(0xFFFF) as u16).to_be_bytes()
+}
+
+//Carga: [cantidad (u32), semilla (u32), reloj en kHz (u16), 0 para no cambiarlo]
+//Respuesta: [estado, reloj en Hz (u32), transacciones (u32), discrepancias (u32),
+//errores de paridad (u32), duracion en us (u32), transacciones por segundo (u32)]
+pub async fn spi_stress(
+    carga: Vec<u8>,
+    registro: Option<u8>,
+    rx: &mut tokio::sync::broadcast::Receiver<Vec<u8>>,
+    tx: &tokio::sync::mpsc::Sender<PeticionSpi>,
+    verbose: bool,
+) -> Vec<u8> {
+    let Some(registro) = registro else {
+        if verbose {
+            println!("No spi_test scratch_register configured");
+        }
+        return vec![0xF0];
+    };
+    if carga.len() != 10 {
+        if verbose {
+            println!("Invalid stress test parameters");
+        }
+        return vec![0xF0];
+    }
+    let cantidad = u32::from_be_bytes([carga[0], carga[1], carga[2], carga[3]]);
+    let semilla = u32::from_be_bytes([carga[4], carga[5], carga[6], carga[7]]);
+    let reloj = u16::from_be_bytes([carga[8], carga[9]]) as u32 * 1000;
+
+    let anterior = match reloj_pic(reloj, rx, tx).await {
+        Some(hz) => hz,
+        None => return vec![0xF0],
+    };
+    let reloj = if reloj == 0 { anterior } else { reloj };
+
+    if verbose {
+        println!(
+            "Stress testing with {} patterns at {} Hz, seed {:X}",
+            cantidad, reloj, semilla
+        );
+    }
+    let estadisticas = verificar_registro(cantidad, semilla, registro, rx, tx).await;
+    if reloj != anterior {
+        reloj_pic(anterior, rx, tx).await;
+    }
+    if verbose {
+        println!(
+            "Stress testing finished, {} transactions, {} mismatches, {} parity errors in {:?}",
+            estadisticas.transacciones,
+            estadisticas.discrepancias,
+            estadisticas.errores_paridad,
+            estadisticas.duracion
+        );
+    }
+
+    let mut respuesta = vec![0];
+    respuesta.extend_from_slice(&reloj.to_be_bytes());
+    respuesta.extend_from_slice(&estadisticas.transacciones.to_be_bytes());
+    respuesta.extend_from_slice(&estadisticas.discrepancias.to_be_bytes());
+    respuesta.extend_from_slice(&estadisticas.errores_paridad.to_be_bytes());
+    let micros = estadisticas.duracion.as_micros().min(u32::MAX as u128) as u32;
+    respuesta.extend_from_slice(&micros.to_be_bytes());
+    respuesta.extend_from_slice(&estadisticas.por_segundo().to_be_bytes());
+    respuesta
+}
+
+//Carga: [inicio, fin y paso en kHz (u16), patrones por frecuencia (u32)],
+//sin carga se usan los valores del archivo de configuracion
+//Respuesta: [estado, frecuencia mas alta sin errores en Hz (u32, 0 si no hubo),
+//por frecuencia: reloj en Hz (u32), transacciones (u32), errores (u32)]
+pub async fn spi_sweep(
+    carga: Vec<u8>,
+    config: &config::SpiTest,
+    rx: &mut tokio::sync::broadcast::Receiver<Vec<u8>>,
+    tx: &tokio::sync::mpsc::Sender<PeticionSpi>,
+    verbose: bool,
+) -> Vec<u8> {
+    let (inicio, fin, paso, cantidad) = match carga.len() {
+        0 => (
+            config.sweep_start_khz,
+            config.sweep_stop_khz,
+            config.sweep_step_khz,
+            config.sweep_patterns,
+        ),
+        10 => (
+            u16::from_be_bytes([carga[0], carga[1]]),
+            u16::from_be_bytes([carga[2], carga[3]]),
+            u16::from_be_bytes([carga[4], carga[5]]),
+            u32::from_be_bytes([carga[6], carga[7], carga[8], carga[9]]),
+        ),
+        _ => (0, 0, 0, 0),
+    };
+    let Some(registro) = config.scratch_register else {
+        if verbose {
+            println!("No spi_test scratch_register configured");
+        }
+        return vec![0xF0];
+    };
+    if inicio == 0 || paso == 0 || inicio > fin || cantidad == 0 {
+        if verbose {
+            println!("Invalid sweep parameters");
+        }
+        return vec![0xF0];
+    }
+
+    let anterior = match reloj_pic(0, rx, tx).await {
+        Some(hz) => hz,
+        None => return vec![0xF0],
+    };
+    let mut estado = 0;
+    let mut maxima = 0;
+    let mut puntos = Vec::new();
+
+    for khz in (inicio..=fin).step_by(paso as usize) {
+        let hz = khz as u32 * 1000;
+        if reloj_pic(hz, rx, tx).await.is_none() {
+            estado = 0xF0;
+            break;
+        }
+        let estadisticas = verificar_registro(cantidad, 0, registro, rx, tx).await;
+        if verbose {
+            println!(
+                "Sweep {} Hz: {} errors in {} transactions",
+                hz,
+                estadisticas.errores(),
+                estadisticas.transacciones
+            );
+        }
+        if estadisticas.errores() == 0 {
+            maxima = hz;
+        }
+        puntos.extend_from_slice(&hz.to_be_bytes());
+        puntos.extend_from_slice(&estadisticas.transacciones.to_be_bytes());
+        puntos.extend_from_slice(&estadisticas.errores().to_be_bytes());
     }
+    reloj_pic(anterior, rx, tx).await;
+
     if verbose {
-        println!("Stress testing finished");
+        println!("Sweep finished, highest clock without errors {} Hz", maxima);
     }
-    spi_core(1, 0, rx, tx).await
+    let mut respuesta = vec![estado];
+    respuesta.extend_from_slice(&maxima.to_be_bytes());
+    respuesta.extend_from_slice(&puntos);
+    respuesta
 }
 
+//msg: [opcode, dispositivo << 4 | flags, largo], flag 1: sin paridad
+//Respuesta: [estado, bytes recibidos...], estado 0xF0 si la peticion es invalida
+//y 0x01 si hubo errores de paridad en lo recibido
+pub async fn spi_transfer(
+    msg: u32,
+    mut datos: Vec<u8>,
+    rx: &mut tokio::sync::broadcast::Receiver<Vec<u8>>,
+    tx: &tokio::sync::mpsc::Sender<PeticionSpi>,
+) -> Vec<u8> {
+    let dispositivo = ((msg >> 20) & 0x0F) as usize;
+    let sin_paridad = msg & 0x00010000 != 0;
+    if datos.is_empty()
+        || datos.len() > SPI_MAX_TRANSFER
+        || (!sin_paridad && !datos.len().is_multiple_of(2))
+    {
+        return vec![0xF0];
+    }
+    if !sin_paridad {
+        paridad_palabras(&mut datos);
+    }
+
+    tx.send(PeticionSpi::Transferencia(dispositivo, datos, !sin_paridad))
+        .await
+        .unwrap();
+
+    rx.recv().await.unwrap()
+}
+
+//Respuesta: [errores (u32), palabras verificadas (u32), ultima palabra erronea (u16)]
+pub async fn spi_parity(
+    msg: u32,
+    rx: &mut tokio::sync::broadcast::Receiver<Vec<u8>>,
+    tx: &tokio::sync::mpsc::Sender<PeticionSpi>,
+) -> Vec<u8> {
+    tx.send(PeticionSpi::Paridad(msg & 0x00FF0000 == 0x00010000))
+        .await
+        .unwrap();
+    rx.recv().await.unwrap()
+}
+
+//Respuesta del handler: [palabra alta, palabra baja, estado de paridad]
 async fn spi_core(
     len: u8,
     msg: u32,
-    rx: &mut tokio::sync::broadcast::Receiver<[u8; 2]>,
-    tx: &tokio::sync::mpsc::Sender<[u8; 5]>,
-) -> [u8; 2] {
+    rx: &mut tokio::sync::broadcast::Receiver<Vec<u8>>,
+    tx: &tokio::sync::mpsc::Sender<PeticionSpi>,
+) -> Result<u16, u16> {
     let msg = parity_set(msg);
     let mut arr = [len; 5];
     arr[1..].clone_from_slice(&msg.to_be_bytes());
 
-    tx.send(arr).await.unwrap();
+    tx.send(PeticionSpi::Palabras(arr)).await.unwrap();
 
-    rx.recv().await.unwrap()
+    let respuesta = rx.recv().await.unwrap();
+    let palabra = u16::from_be_bytes([respuesta[0], respuesta[1]]);
+    if respuesta[2] != 0 {
+        return Err(palabra);
+    }
+    Ok(palabra)
+}
+
+//Paridad par en el bit mas alto de cada palabra de 16 bits
+fn paridad_palabras(datos: &mut [u8]) {
+    for palabra in datos.chunks_exact_mut(2) {
+        let valor = u16::from_be_bytes([palabra[0], palabra[1]]) & 0x7FFF;
+        if !valor.count_ones().is_multiple_of(2) {
+            palabra[0] |= 0x80;
+        } else {
+            palabra[0] &= 0x7F;
+        }
+    }
 }
 
 fn parity_set(dato: u32) -> u32 {
     let msgh = dato & 0xFFFF0000;
     let msgl = dato & 0x0000FFFF;
     let mut ret = dato;
-    if msgh.count_ones() % 2 != 0 {
+    if !msgh.count_ones().is_multiple_of(2) {
         ret ^= 0x80000000
     }
-    if msgl.count_ones() % 2 != 0 {
+    if !msgl.count_ones().is_multiple_of(2) {
         ret ^= 0x00008000
     }
     ret
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn paridad_de_comandos() {
+        assert_eq!(parity_set(0x3C000000), 0x3C000000);
+        assert_eq!(parity_set(0x3C010000), 0xBC010000);
+        assert_eq!(parity_set(0x25100001), 0x25108001);
+        assert_eq!(parity_set(0x00000003), 0x00000003);
+    }
+
+    #[test]
+    fn paridad_de_tramas() {
+        let mut datos = [0x00, 0x01, 0xFF, 0xFF, 0x00, 0x03];
+        paridad_palabras(&mut datos);
+        assert_eq!(datos, [0x80, 0x01, 0xFF, 0xFF, 0x00, 0x03]);
+        assert_eq!(palabras_erroneas(&datos).count(), 0);
+    }
+
+    #[test]
+    fn palabras_con_error() {
+        let datos = [0x80, 0x01, 0x00, 0x01, 0x00, 0x03, 0x7F];
+        assert_eq!(palabras_erroneas(&datos).collect::<Vec<u16>>(), [0x0001]);
+    }
+
+    #[test]
+    fn cuenta_errores() {
+        let mut paridad = ErroresParidad::default();
+        assert_eq!(
+            paridad.verificar(&[0x00, 0x01, 0x00, 0x07, 0x80, 0x01], false),
+            2
+        );
+        assert_eq!(paridad.verificar(&[0x00, 0x03], false), 0);
+        assert_eq!(paridad.palabras, 4);
+        assert_eq!(paridad.errores, 2);
+        assert_eq!(paridad.ultima_erronea, 0x0007);
+    }
+
+    #[test]
+    fn respuesta_sin_pisar_los_datos() {
+        assert_eq!(marcar_paridad(Ok(0x8001)), [0x00, 0x01]);
+        assert_eq!(marcar_paridad(Ok(0x1234)), [0x12, 0x34]);
+        assert_eq!(marcar_paridad(Err(0x0001)), [0x80, 0x01]);
+        assert_eq!(marcar_paridad(Err(0xF0F1)), [0xF0, 0xF1]);
+    }
+
+    //PIC de prueba con un solo registro: arriba de 500 kHz lee con el bit 0 cambiado
+    //y arriba de 800 kHz ademas con error de paridad
+    fn pic_de_prueba() -> (
+        tokio::sync::broadcast::Receiver<Vec<u8>>,
+        tokio::sync::mpsc::Sender<PeticionSpi>,
+    ) {
+        let (tx, mut peticiones) = tokio::sync::mpsc::channel(1);
+        let (respuestas, rx) = tokio::sync::broadcast::channel(16);
+        tokio::spawn(async move {
+            let mut registro = 0u16;
+            let mut reloj = 100_000u32;
+            while let Some(peticion) = peticiones.recv().await {
+                let respuesta = match peticion {
+                    PeticionSpi::Palabras([_, a, b, c, d]) => {
+                        let msg = u32::from_be_bytes([a, b, c, d]);
+                        if msg & 0x7F000000 == 0x25000000 {
+                            registro = (msg & 0x7FFF) as u16;
+                        }
+                        let leido = if reloj > 500_000 {
+                            registro ^ 1
+                        } else {
+                            registro
+                        };
+                        let [alto, bajo] = leido.to_be_bytes();
+                        vec![alto, bajo, (reloj > 800_000) as u8]
+                    }
+                    PeticionSpi::RelojPic(hz) => {
+                        let mut respuesta = vec![0];
+                        respuesta.extend_from_slice(&reloj.to_be_bytes());
+                        if hz > 0 {
+                            reloj = hz;
+                        }
+                        respuesta
+                    }
+                    _ => vec![0xF0],
+                };
+                respuestas.send(respuesta).unwrap();
+            }
+        });
+        (rx, tx)
+    }
+
+    fn carga_de_stress(cantidad: u32, semilla: u32, khz: u16) -> Vec<u8> {
+        let mut carga = cantidad.to_be_bytes().to_vec();
+        carga.extend_from_slice(&semilla.to_be_bytes());
+        carga.extend_from_slice(&khz.to_be_bytes());
+        carga
+    }
+
+    fn u32_en(respuesta: &[u8], posicion: usize) -> u32 {
+        u32::from_be_bytes(respuesta[posicion..posicion + 4].try_into().unwrap())
+    }
+
+    #[test]
+    fn patrones_pseudoaleatorios() {
+        let mut estado = SEMILLA_POR_DEFECTO;
+        let primero = xorshift(&mut estado);
+        assert_ne!(primero, 0);
+        assert_ne!(xorshift(&mut estado), primero);
+        let mut otro = SEMILLA_POR_DEFECTO;
+        assert_eq!(xorshift(&mut otro), primero);
+    }
+
+    #[test]
+    fn estadisticas_de_la_prueba() {
+        let estadisticas = Estadisticas {
+            transacciones: 2000,
+            discrepancias: 3,
+            errores_paridad: 2,
+            duracion: Duration::from_millis(500),
+        };
+        assert_eq!(estadisticas.errores(), 5);
+        assert_eq!(estadisticas.por_segundo(), 4000);
+        assert_eq!(Estadisticas::default().por_segundo(), 0);
+    }
+
+    #[tokio::test]
+    async fn stress_sin_errores() {
+        let (mut rx, tx) = pic_de_prueba();
+        let respuesta =
+            spi_stress(carga_de_stress(50, 7, 0), Some(0x20), &mut rx, &tx, false).await;
+        assert_eq!(respuesta.len(), 25);
+        assert_eq!(respuesta[0], 0);
+        assert_eq!(u32_en(&respuesta, 1), 100_000);
+        assert_eq!(u32_en(&respuesta, 5), 100);
+        assert_eq!(u32_en(&respuesta, 9), 0);
+        assert_eq!(u32_en(&respuesta, 13), 0);
+    }
+
+    #[tokio::test]
+    async fn stress_con_errores_vuelve_al_reloj_anterior() {
+        let (mut rx, tx) = pic_de_prueba();
+        let respuesta =
+            spi_stress(carga_de_stress(50, 7, 900), Some(0x20), &mut rx, &tx, false).await;
+        assert_eq!(respuesta[0], 0);
+        assert_eq!(u32_en(&respuesta, 1), 900_000);
+        assert_eq!(u32_en(&respuesta, 9), 50);
+        assert_eq!(u32_en(&respuesta, 13), 100);
+        assert_eq!(reloj_pic(0, &mut rx, &tx).await, Some(100_000));
+
+        assert_eq!(
+            spi_stress_test(0x5E000000 | 50, Some(0x20), &mut rx, &tx, false).await,
+            [0x00, 0x00]
+        );
+    }
+
+    #[tokio::test]
+    async fn stress_sin_registro_o_con_carga_invalida() {
+        let (mut rx, tx) = pic_de_prueba();
+        assert_eq!(
+            spi_stress(carga_de_stress(50, 7, 0), None, &mut rx, &tx, false).await,
+            [0xF0]
+        );
+        assert_eq!(
+            spi_stress(vec![0; 9], Some(0x20), &mut rx, &tx, false).await,
+            [0xF0]
+        );
+    }
+
+    #[tokio::test]
+    async fn barrido_encuentra_el_reloj_mas_alto_sin_errores() {
+        let (mut rx, tx) = pic_de_prueba();
+        let config = config::SpiTest {
+            scratch_register: Some(0x20),
+            sweep_patterns: 10,
+            ..config::SpiTest::default()
+        };
+        let respuesta = spi_sweep(Vec::new(), &config, &mut rx, &tx, false).await;
+        assert_eq!(respuesta[0], 0);
+        assert_eq!(u32_en(&respuesta, 1), 500_000);
+        assert_eq!(respuesta.len(), 5 + 10 * 12);
+        let puntos: Vec<(u32, u32, u32)> = respuesta[5..]
+            .chunks_exact(12)
+            .map(|p| (u32_en(p, 0), u32_en(p, 4), u32_en(p, 8)))
+            .collect();
+        assert_eq!(puntos[0], (100_000, 20, 0));
+        assert_eq!(puntos[5], (600_000, 20, 10));
+        assert_eq!(puntos[9], (1_000_000, 20, 30));
+        assert_eq!(reloj_pic(0, &mut rx, &tx).await, Some(100_000));
+    }
+
+    #[tokio::test]
+    async fn barrido_con_parametros_de_la_carga() {
+        let (mut rx, tx) = pic_de_prueba();
+        let config = config::SpiTest {
+            scratch_register: Some(0x20),
+            ..config::SpiTest::default()
+        };
+        let mut carga = Vec::new();
+        for khz in [200u16, 800, 300] {
+            carga.extend_from_slice(&khz.to_be_bytes());
+        }
+        carga.extend_from_slice(&5u32.to_be_bytes());
+        let respuesta = spi_sweep(carga, &config, &mut rx, &tx, false).await;
+        assert_eq!(u32_en(&respuesta, 1), 500_000);
+        assert_eq!(respuesta.len(), 5 + 3 * 12);
+
+        //Inicio mayor que el fin
+        let mut carga = Vec::new();
+        for khz in [800u16, 200, 100] {
+            carga.extend_from_slice(&khz.to_be_bytes());
+        }
+        carga.extend_from_slice(&5u32.to_be_bytes());
+        assert_eq!(spi_sweep(carga, &config, &mut rx, &tx, false).await, [0xF0]);
+        let sin_registro = config::SpiTest::default();
+        assert_eq!(
+            spi_sweep(Vec::new(), &sin_registro, &mut rx, &tx, false).await,
+            [0xF0]
+        );
+    }
+}
@@ -3,12 +3,19 @@ use rppal::gpio::{Gpio, OutputPin};
 use std::process::Stdio;
 use tokio::fs;
 use tokio::io::AsyncWriteExt;
-use tokio::process::{ChildStdin, Command};
+use tokio::process::{Child, Command};
+use tokio::time::{timeout, Duration};
+
+use crate::seguro::Listo;
+
+//Lo que se espera a que la señal termine sola antes de matarla
+const ESPERA_DE_LA_SEÑAL: Duration = Duration::from_millis(200);
 
 pub async fn tnr_handler(
     verbose: bool,
     mut rx: tokio::sync::mpsc::Receiver<[u8; 4]>,
     tx: tokio::sync::broadcast::Sender<[u8; 2]>,
+    mut seguro: tokio::sync::broadcast::Receiver<Listo>,
 ) {
     let mut registros = [1; 6];
     registros[0] = 100;
@@ -19,7 +26,19 @@ pub async fn tnr_handler(
     let mut tnr = None;
 
     loop {
-        let msg = rx.recv().await.unwrap();
+        let msg = tokio::select! {
+            msg = rx.recv() => msg.unwrap(),
+            Ok(_listo) = seguro.recv() => {
+                if verbose {
+                    println!("Stopping TnR");
+                }
+                //Primero se corta la alimentacion, la señal puede no terminar bien
+                registros[5] = 0;
+                power_enable(0, &mut power_enable_pin, verbose);
+                terminar_señal(tnr.take()).await;
+                continue;
+            }
+        };
         let mut arr = [0; 2];
         arr.clone_from_slice(&msg[2..]);
         let valor_nuevo = <u16>::from_be_bytes(arr);
@@ -66,6 +85,15 @@ pub async fn tnr(
     rx.recv().await.unwrap()
 }
 
+//Registros 0 a 3: periodo, ancho del pulso, margen inicial y margen final
+//Los margenes tienen que entrar en el pulso y el pulso en el periodo
+pub fn señal_valida(registros: [u16; 4]) -> bool {
+    let [periodo, ancho_del_pulso, margen_inicial, margen_final] = registros;
+    periodo > 0
+        && ancho_del_pulso <= periodo
+        && margen_inicial as u32 + margen_final as u32 <= ancho_del_pulso as u32
+}
+
 async fn actualizar(verbose: bool, reg: [u16; 6], tnr: Señal) -> Señal {
     if verbose {
         println!("generando señal {:?}", reg);
@@ -102,17 +130,26 @@ async fn generar_señal(
     ejecutar_señal(duracion_del_bit, cantidad_de_pulsos).await
 }
 
+//Se le pide que termine y si no lo hace a tiempo se la mata
 async fn terminar_señal(señal_anterior: Señal) {
-    match señal_anterior {
-        Some(mut señal) => {
-            let msg: [u8; 1] = [0; 1];
-            señal
-                .write(&msg)
-                .await
-                .expect("No se puedo terminar la señal");
-            drop(señal);
+    let Some(mut señal) = señal_anterior else {
+        return;
+    };
+    let mut stdin = señal.stdin.take();
+    let pedido = async {
+        if let Some(stdin) = stdin.as_mut() {
+            stdin.write_all(&[0]).await?;
         }
-        None => {}
+        señal.wait().await
+    };
+    let motivo = match timeout(ESPERA_DE_LA_SEÑAL, pedido).await {
+        Ok(Ok(_)) => return,
+        Ok(Err(e)) => e.to_string(),
+        Err(_) => String::from("timed out"),
+    };
+    println!("TnR signal did not stop ({}), killing it", motivo);
+    if let Err(e) = señal.kill().await {
+        println!("Failed to kill the TnR signal: {}", e);
     }
 }
 
@@ -154,7 +191,7 @@ async fn ejecutar_señal(bit_time: u16, count: u16) -> Señal {
     let bit_time = bit_time.to_string();
     let count = count.to_string();
 
-    let mut señal = Command::new("taskset")
+    let señal = Command::new("taskset")
         .arg("-c")
         .arg("3")
         .arg("python3")
@@ -166,7 +203,7 @@ async fn ejecutar_señal(bit_time: u16, count: u16) -> Señal {
         .spawn()
         .expect("Falló el lanzar la señal");
 
-    señal.stdin.take()
+    Some(señal)
 }
 
-type Señal = Option<ChildStdin>;
+type Señal = Option<Child>;
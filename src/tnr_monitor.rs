@@ -30,13 +30,10 @@ pub async fn monitor_handler(
                 let pin_ret;
 
                 loop {
-                    match monitor_pin.poll_interrupt(true, Some(Duration::from_millis(timeout_period)))
+                    if let Ok(l) = monitor_pin.poll_interrupt(true, Some(Duration::from_millis(timeout_period)))
                     {
-                        Ok(l) => {
-                            pin_ret = l;
-                            break;
-                        }
-                        Err(_) => {}
+                        pin_ret = l;
+                        break;
                     };
                 }
 
@@ -125,15 +122,12 @@ async fn counter(
     monitor_pin.set_interrupt(Trigger::RisingEdge).unwrap();
 
     loop{
-        match rx.try_recv() {
-            Ok(_) => { 
-                if verbose {
-                    println!("Counted {}", count);
-                }
-                monitor_pin.clear_interrupt().unwrap();
-                return count;
-            },
-            Err(_) => { },
+        if rx.try_recv().is_ok() {
+            if verbose {
+                println!("Counted {}", count);
+            }
+            monitor_pin.clear_interrupt().unwrap();
+            return count;
         }
 
         match monitor_pin.poll_interrupt(true, Some(Duration::from_millis(timeout_period)))
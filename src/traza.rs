@@ -0,0 +1,331 @@
+use std::collections::VecDeque;
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use crate::config;
+
+pub type TrazaSpi = Arc<Mutex<Traza>>;
+
+#[derive(Clone)]
+pub struct Transaccion {
+    //Microsegundos desde que se borro la traza
+    pub instante_us: u64,
+    pub dispositivo: String,
+    pub reloj_hz: u32,
+    pub modo: u8,
+    pub lsb_primero: bool,
+    pub enviado: Vec<u8>,
+    pub recibido: Vec<u8>,
+    //Solo para el PIC: true si todas las palabras recibidas tienen paridad par
+    pub paridad: Option<bool>,
+}
+
+/* TRAZA DE TRANSACCIONES SPI EN UN BUFFER CIRCULAR */
+pub struct Traza {
+    activa: bool,
+    capacidad: usize,
+    descartadas: u32,
+    inicio: Instant,
+    transacciones: VecDeque<Transaccion>,
+}
+
+impl Traza {
+    pub fn new(config: &config::SpiTrace) -> Self {
+        Traza {
+            activa: config.enabled,
+            capacidad: config.capacity,
+            descartadas: 0,
+            inicio: Instant::now(),
+            transacciones: VecDeque::new(),
+        }
+    }
+
+    pub fn activa(&self) -> bool {
+        self.activa
+    }
+
+    pub fn instante_us(&self) -> u64 {
+        self.inicio.elapsed().as_micros() as u64
+    }
+
+    //Con el buffer lleno se pierde la transaccion mas vieja
+    pub fn registrar(&mut self, transaccion: Transaccion) {
+        if !self.activa || self.capacidad == 0 {
+            return;
+        }
+        if self.transacciones.len() >= self.capacidad {
+            self.transacciones.pop_front();
+            self.descartadas = self.descartadas.saturating_add(1);
+        }
+        self.transacciones.push_back(transaccion);
+    }
+
+    fn borrar(&mut self) {
+        self.transacciones.clear();
+        self.descartadas = 0;
+        self.inicio = Instant::now();
+    }
+}
+
+//Arma el texto de una descarga con un limite de bytes
+type Formato = fn(&[Transaccion], usize) -> String;
+
+//Las descargas terminan en la ultima transaccion que entra en el limite
+fn recorte(hechas: usize, total: usize) -> Option<String> {
+    (hechas < total).then(|| format!("truncated: {} of {} transactions", hechas, total))
+}
+
+fn csv(transacciones: &[Transaccion], limite: usize) -> String {
+    let mut csv = String::from("time_us,device,clock_hz,mode,tx,rx,parity\n");
+    let mut hechas = 0;
+    for t in transacciones {
+        let paridad = match t.paridad {
+            Some(true) => "ok",
+            Some(false) => "error",
+            None => "",
+        };
+        let linea = format!(
+            "{},{},{},{},{},{},{}\n",
+            t.instante_us,
+            t.dispositivo,
+            t.reloj_hz,
+            t.modo,
+            hexa(&t.enviado),
+            hexa(&t.recibido),
+            paridad
+        );
+        if csv.len() + linea.len() > limite {
+            break;
+        }
+        csv.push_str(&linea);
+        hechas += 1;
+    }
+    if let Some(recorte) = recorte(hechas, transacciones.len()) {
+        let _ = writeln!(csv, "# {}", recorte);
+    }
+    csv
+}
+
+//Reconstruye las señales del bus a partir del reloj y el modo de cada transaccion
+fn vcd(transacciones: &[Transaccion], limite: usize) -> String {
+    let mut dispositivos: Vec<&str> = Vec::new();
+    for t in transacciones {
+        if !dispositivos.contains(&t.dispositivo.as_str()) {
+            dispositivos.push(&t.dispositivo);
+        }
+    }
+
+    let mut cabecera = String::from("$timescale 1ns $end\n$scope module spi $end\n");
+    cabecera.push_str("$var wire 1 c sclk $end\n");
+    cabecera.push_str("$var wire 1 o mosi $end\n");
+    cabecera.push_str("$var wire 1 i miso $end\n");
+    for (n, nombre) in dispositivos.iter().enumerate() {
+        let _ = writeln!(cabecera, "$var wire 1 s{} cs_{} $end", n, nombre);
+    }
+    cabecera.push_str("$upscope $end\n$enddefinitions $end\n");
+
+    let mut vcd = String::from("#0\n");
+    let reposo = transacciones.first().map_or(0, |t| t.modo >> 1);
+    let _ = writeln!(vcd, "{}c\n0o\n0i", reposo);
+    for n in 0..dispositivos.len() {
+        let _ = writeln!(vcd, "1s{}", n);
+    }
+
+    let mut fin = 0;
+    let mut hechas = 0;
+    for t in transacciones {
+        let periodo = (1_000_000_000 / t.reloj_hz.max(1) as u64).max(2);
+        let medio = periodo / 2;
+        let cpol = t.modo >> 1;
+        let cpha = t.modo & 1 != 0;
+        let cs = dispositivos
+            .iter()
+            .position(|d| *d == t.dispositivo)
+            .unwrap();
+
+        let mut señales = String::new();
+        let inicio = (t.instante_us * 1000).max(fin + periodo);
+        let _ = writeln!(señales, "#{}\n{}c\n0s{}", inicio, cpol, cs);
+        let datos = inicio + medio;
+        let bits = t.enviado.len().min(t.recibido.len()) as u64 * 8;
+        for n in 0..bits {
+            let byte = (n / 8) as usize;
+            let bit = if t.lsb_primero { n % 8 } else { 7 - n % 8 };
+            let enviado = (t.enviado[byte] >> bit) & 1;
+            let recibido = (t.recibido[byte] >> bit) & 1;
+            let ranura = datos + n * periodo;
+            //Con CPHA el dato cambia en el primer flanco, sin CPHA medio periodo antes
+            if cpha {
+                let _ = writeln!(
+                    señales,
+                    "#{}\n{}c\n{}o\n{}i",
+                    ranura + medio,
+                    1 - cpol,
+                    enviado,
+                    recibido
+                );
+                let _ = writeln!(señales, "#{}\n{}c", ranura + periodo, cpol);
+            } else {
+                let _ = writeln!(señales, "#{}", ranura);
+                if n > 0 {
+                    let _ = writeln!(señales, "{}c", cpol);
+                }
+                let _ = writeln!(señales, "{}o\n{}i", enviado, recibido);
+                let _ = writeln!(señales, "#{}\n{}c", ranura + medio, 1 - cpol);
+            }
+        }
+        if !cpha && bits > 0 {
+            let _ = writeln!(señales, "#{}\n{}c", datos + bits * periodo, cpol);
+        }
+        let final_de_transaccion = datos + bits * periodo + medio;
+        let _ = writeln!(señales, "#{}\n1s{}", final_de_transaccion, cs);
+
+        if cabecera.len() + vcd.len() + señales.len() > limite {
+            break;
+        }
+        vcd.push_str(&señales);
+        fin = final_de_transaccion;
+        hechas += 1;
+    }
+    if let Some(recorte) = recorte(hechas, transacciones.len()) {
+        cabecera = format!("$comment {} $end\n{}", recorte, cabecera);
+    }
+    cabecera + &vcd
+}
+
+fn hexa(datos: &[u8]) -> String {
+    datos.iter().fold(String::new(), |mut s, b| {
+        let _ = write!(s, "{:02X}", b);
+        s
+    })
+}
+
+//msg: [opcode, comando << 4], comando 0: iniciar, 1: detener, 2: borrar
+//Respuesta: [0, 1 si la traza esta activa]
+pub fn spi_trace(msg: u32, traza: &TrazaSpi, verbose: bool) -> [u8; 2] {
+    let mut traza = traza.lock().unwrap();
+    match (msg >> 20) & 0x0F {
+        0 => traza.activa = true,
+        1 => traza.activa = false,
+        2 => traza.borrar(),
+        _ => {
+            if verbose {
+                println!("Invalid trace command");
+            }
+            return [0xF0, 0xF0];
+        }
+    }
+    if verbose {
+        println!(
+            "Spi trace {}, {} transactions",
+            if traza.activa { "on" } else { "off" },
+            traza.transacciones.len()
+        );
+    }
+    [0, traza.activa as u8]
+}
+
+//comando 3: estado, [activa, transacciones (u32), capacidad (u32), descartadas (u32)]
+//4: descarga en CSV, 5: descarga en VCD
+//El texto se arma fuera del runtime con una copia, sin tener la traza bloqueada
+pub async fn spi_trace_download(msg: u32, traza: &TrazaSpi, limite: usize) -> Vec<u8> {
+    let (transacciones, formato): (Vec<Transaccion>, Formato) = {
+        let traza = traza.lock().unwrap();
+        match (msg >> 20) & 0x0F {
+            3 => {
+                let mut respuesta = vec![traza.activa as u8];
+                respuesta.extend_from_slice(&(traza.transacciones.len() as u32).to_be_bytes());
+                respuesta.extend_from_slice(&(traza.capacidad as u32).to_be_bytes());
+                respuesta.extend_from_slice(&traza.descartadas.to_be_bytes());
+                return respuesta;
+            }
+            4 => (traza.transacciones.iter().cloned().collect(), csv),
+            5 => (traza.transacciones.iter().cloned().collect(), vcd),
+            _ => return vec![0xF0],
+        }
+    };
+    tokio::task::spawn_blocking(move || formato(&transacciones, limite).into_bytes())
+        .await
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn transaccion(instante_us: u64, enviado: u8, recibido: u8) -> Transaccion {
+        Transaccion {
+            instante_us,
+            dispositivo: String::from("pic"),
+            reloj_hz: 1_000_000,
+            modo: 0,
+            lsb_primero: false,
+            enviado: vec![enviado],
+            recibido: vec![recibido],
+            paridad: Some(true),
+        }
+    }
+
+    fn traza(capacidad: usize) -> TrazaSpi {
+        Arc::new(Mutex::new(Traza::new(&config::SpiTrace {
+            enabled: true,
+            capacity: capacidad,
+            ..config::SpiTrace::default()
+        })))
+    }
+
+    #[tokio::test]
+    async fn el_buffer_descarta_las_mas_viejas() {
+        let traza = traza(2);
+        for n in 0..3 {
+            traza.lock().unwrap().registrar(transaccion(n, 0, 0));
+        }
+        assert_eq!(
+            spi_trace_download(0x57300000, &traza, usize::MAX).await,
+            [1, 0, 0, 0, 2, 0, 0, 0, 2, 0, 0, 0, 1]
+        );
+        let csv = spi_trace_download(0x57400000, &traza, usize::MAX).await;
+        let csv = String::from_utf8(csv).unwrap();
+        assert!(csv.starts_with("time_us,device,clock_hz,mode,tx,rx,parity\n1,pic,"));
+
+        //Detenida no registra, y borrarla reinicia la cuenta
+        assert_eq!(spi_trace(0x57100000, &traza, false), [0, 0]);
+        traza.lock().unwrap().registrar(transaccion(3, 0, 0));
+        assert_eq!(spi_trace(0x57200000, &traza, false), [0, 0]);
+        assert_eq!(
+            spi_trace_download(0x57300000, &traza, usize::MAX).await,
+            [0, 0, 0, 0, 0, 0, 0, 0, 2, 0, 0, 0, 0]
+        );
+    }
+
+    #[test]
+    fn csv_recortado_al_limite() {
+        let transacciones = [transaccion(10, 0xA5, 0x5A), transaccion(20, 0x01, 0x02)];
+        let completo = csv(&transacciones, usize::MAX);
+        assert_eq!(
+            completo,
+            "time_us,device,clock_hz,mode,tx,rx,parity\n\
+             10,pic,1000000,0,A5,5A,ok\n\
+             20,pic,1000000,0,01,02,ok\n"
+        );
+        let recortado = csv(&transacciones, completo.len() - 1);
+        assert!(
+            recortado.ends_with("10,pic,1000000,0,A5,5A,ok\n# truncated: 1 of 2 transactions\n")
+        );
+    }
+
+    #[test]
+    fn vcd_en_modo_0() {
+        let señales = vcd(&[transaccion(0, 0x80, 0x01)], usize::MAX);
+        assert!(señales.starts_with("$timescale 1ns $end\n"));
+        assert!(señales.contains("$var wire 1 s0 cs_pic $end\n"));
+        //Chip select a los 1000 ns y el primer bit medio periodo despues
+        assert!(señales.contains("#1000\n0c\n0s0\n#1500\n1o\n0i\n#2000\n1c\n"));
+        assert!(señales.contains("#8500\n0c\n0o\n1i\n#9000\n1c\n"));
+        assert!(señales.ends_with("#9500\n0c\n#10000\n1s0\n"));
+
+        let recortado = vcd(&[transaccion(0, 0x80, 0x01)], 10);
+        assert!(recortado.starts_with("$comment truncated: 0 of 1 transactions $end\n"));
+    }
+}
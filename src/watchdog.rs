@@ -0,0 +1,129 @@
+use tokio::time::{sleep_until, Duration, Instant};
+
+use crate::config;
+
+/* WATCHDOG POR CONEXION */
+pub struct Watchdog {
+    habilitado: bool,
+    timeout: Option<Duration>,
+    armado: bool,
+    ultimo: Instant,
+}
+
+impl Watchdog {
+    pub fn new(config: &config::Watchdog) -> Self {
+        Watchdog {
+            habilitado: config.enabled,
+            timeout: match config.timeout_ms {
+                0 => None,
+                ms => Some(Duration::from_millis(ms)),
+            },
+            armado: false,
+            ultimo: Instant::now(),
+        }
+    }
+
+    //Se arma cuando el cliente cambia alguna salida
+    pub fn armar(&mut self, mensaje: u32) {
+        if self.habilitado && modifica_salidas(mensaje) {
+            self.armado = true;
+        }
+    }
+
+    pub fn alimentar(&mut self) {
+        self.ultimo = Instant::now();
+    }
+
+    pub fn armado(&self) -> bool {
+        self.armado
+    }
+
+    pub fn con_timeout(&self) -> bool {
+        self.armado && self.timeout.is_some()
+    }
+
+    pub async fn vencido(&self) {
+        if let Some(timeout) = self.timeout {
+            sleep_until(self.ultimo + timeout).await;
+        }
+    }
+}
+
+fn modifica_salidas(mensaje: u32) -> bool {
+    match mensaje & 0x7F000000 {
+        0x2A000000 | 0x2B000000 | 0x2C000000 | 0x23000000 | 0x2D000000 | 0x3D000000
+        | 0x53000000 => true,
+        //Los comandos 2 y 3 del banco solo leen
+        0x2E000000 => (mensaje >> 20) & 0x0F < 2,
+        //Solo empezar una forma de onda
+        0x47000000 => (mensaje >> 20) & 0x0F == 0,
+        //Programar mueve los relays y los pines del ICSP, los demas suben o consultan
+        0x50000000 => matches!((mensaje >> 20) & 0x0F, 2 | 4),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn watchdog(enabled: bool, timeout_ms: u64) -> Watchdog {
+        Watchdog::new(&config::Watchdog {
+            enabled,
+            timeout_ms,
+        })
+    }
+
+    #[test]
+    fn comandos_que_modifican_salidas() {
+        for mensaje in [
+            0x2A000000, 0x2B000000, 0x2C000004, 0x23050001, 0x2D000001, 0x3D000001, 0x53000000,
+            0x2E000001, 0x2E100064, 0x47000000, 0x50200000, 0x50400000,
+        ] {
+            assert!(modifica_salidas(mensaje), "{:08X}", mensaje);
+        }
+    }
+
+    #[test]
+    fn comandos_que_solo_leen() {
+        for mensaje in [
+            0x33050000, 0x3A000000, 0x3B000000, 0x2E200000, 0x2E300000, 0x2E400000, 0x47200000,
+            0x50000010, 0x50100010, 0x50300000, 0x3C100000, 0x48000000,
+        ] {
+            assert!(!modifica_salidas(mensaje), "{:08X}", mensaje);
+        }
+    }
+
+    #[test]
+    fn se_arma_solo_habilitado() {
+        let mut deshabilitado = watchdog(false, 100);
+        deshabilitado.armar(0x2A000000);
+        assert!(!deshabilitado.armado());
+
+        let mut habilitado = watchdog(true, 100);
+        habilitado.armar(0x3A000000);
+        assert!(!habilitado.armado());
+        assert!(!habilitado.con_timeout());
+        habilitado.armar(0x2A000000);
+        assert!(habilitado.armado());
+        assert!(habilitado.con_timeout());
+    }
+
+    #[test]
+    fn sin_timeout_solo_al_desconectar() {
+        let mut watchdog = watchdog(true, 0);
+        watchdog.armar(0x2E000001);
+        assert!(watchdog.armado());
+        assert!(!watchdog.con_timeout());
+    }
+
+    #[tokio::test]
+    async fn vence_sin_comandos() {
+        let mut watchdog = watchdog(true, 50);
+        watchdog.armar(0x2A000000);
+        watchdog.alimentar();
+        let inicio = Instant::now();
+        watchdog.vencido().await;
+        assert!(inicio.elapsed() >= Duration::from_millis(50));
+    }
+}